@@ -1,10 +1,12 @@
-use kube_eye_export_server::{error::ColorEyreInstallSnafu, run};
+use clap::Parser;
+use kube_eye_export_server::{cli::Cli, error::ColorEyreInstallSnafu, run};
 use snafu::ResultExt;
 use tracing::error;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
 async fn main() -> color_eyre::eyre::Result<()> {
+    let cli = Cli::parse();
     color_eyre::install().context(ColorEyreInstallSnafu)?;
     tracing_subscriber::registry()
         .with(
@@ -19,7 +21,7 @@ async fn main() -> color_eyre::eyre::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    if let Err(e) = run().await {
+    if let Err(e) = run(cli).await {
         error!("{e}");
         std::process::exit(1);
     }
@@ -0,0 +1,492 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ResultExt, ensure};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    auth::AuthInfo,
+    cache::CachedReport,
+    config::ArchiveConfig,
+    error::{ForbiddenSnafu, JsonSerializeSnafu, ReportNotFoundSnafu, Result},
+    extractor::ValidatedQuery,
+    jobs::{is_id, new_id},
+    server::{ServerState, report_response},
+    storage::Storage,
+};
+
+/// Longest pause between two retention sweeps.
+const RETENTION_SWEEP: Duration = Duration::from_secs(60);
+
+/// Metadata of an archived report, stored next to it as `{id}.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub id: String,
+    /// User id of the caller the report was rendered for.
+    pub user: String,
+    pub theme: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub size: u64,
+    /// Hex encoded sha256 of the file.
+    pub sha256: String,
+    pub content_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ext: Option<String>,
+}
+
+/// Filters of `GET /api/reports`, all of which must match.
+#[derive(Debug, Default, Deserialize)]
+pub struct ArchiveQuery {
+    pub user: Option<String>,
+    pub theme: Option<String>,
+    /// Part of the report name.
+    pub name: Option<String>,
+    /// Created at or after this time, e.g. `2024-05-01T00:00:00Z`.
+    pub since: Option<DateTime<Utc>>,
+    /// Created before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// At most this many reports, newest first.
+    pub limit: Option<usize>,
+}
+
+impl ArchiveQuery {
+    fn matches(&self, entry: &ArchiveEntry) -> bool {
+        self.user.as_ref().is_none_or(|user| *user == entry.user)
+            && self
+                .theme
+                .as_ref()
+                .is_none_or(|theme| *theme == entry.theme)
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| entry.name.contains(name.as_str()))
+            && self.since.is_none_or(|since| entry.created_at >= since)
+            && self.until.is_none_or(|until| entry.created_at < until)
+    }
+}
+
+/// Rendered reports kept in a [`Storage`], each as `{id}` plus its metadata
+/// in `{id}.json`.
+pub struct Archive {
+    storage: Storage,
+    max_age: Option<Duration>,
+    max_total_bytes: Option<u64>,
+    entries: Mutex<HashMap<String, ArchiveEntry>>,
+}
+
+impl Archive {
+    /// An empty archive in the configured storage; [`Archive::load`] picks up
+    /// the reports archived by previous processes.
+    pub fn new(config: &ArchiveConfig) -> Result<Self> {
+        let storage = Storage::new(&config.dir, config.s3.as_ref())?;
+        Ok(Self::with_storage(storage, config))
+    }
+
+    /// An empty archive in `storage`, with the retention of `config`.
+    pub fn with_storage(storage: Storage, config: &ArchiveConfig) -> Self {
+        Self {
+            storage,
+            max_age: config.max_age_secs.map(Duration::from_secs),
+            max_total_bytes: config.max_total_bytes,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Read the metadata of every report in the storage.
+    pub async fn load(&self) -> Result<()> {
+        let keys: HashSet<String> = self.storage.list().await?.into_iter().collect();
+        let mut entries = HashMap::new();
+        for key in &keys {
+            let Some(id) = key.strip_suffix(".json").filter(|id| is_id(id)) else {
+                continue;
+            };
+            let entry = match self.storage.get(key).await {
+                Ok(json) => serde_json::from_slice::<ArchiveEntry>(&json).ok(),
+                Err(_) => None,
+            };
+            match entry {
+                Some(entry) if entry.id == id && keys.contains(id) => {
+                    entries.insert(entry.id.clone(), entry);
+                }
+                _ => tracing::warn!("skipping unreadable archive entry {}", key),
+            }
+        }
+        tracing::info!("archive {} holds {} reports", self.storage, entries.len());
+        self.lock().extend(entries);
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, ArchiveEntry>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Archive `report`, rendered as `name` with `theme` for `user`.
+    pub async fn store(
+        &self,
+        user: &str,
+        theme: &str,
+        name: &str,
+        report: &CachedReport,
+    ) -> Result<ArchiveEntry> {
+        let entry = ArchiveEntry {
+            id: new_id(),
+            user: user.to_string(),
+            theme: theme.to_string(),
+            name: name.to_string(),
+            created_at: Utc::now(),
+            size: report.body.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(&report.body)),
+            content_type: report.content_type.clone(),
+            ext: report.ext.clone(),
+        };
+        let json = serde_json::to_vec(&entry).context(JsonSerializeSnafu)?;
+        self.storage.put(&entry.id, report.body.clone()).await?;
+        self.storage
+            .put(&format!("{}.json", entry.id), json.into())
+            .await?;
+        self.lock().insert(entry.id.clone(), entry.clone());
+        Ok(entry)
+    }
+
+    /// Reports matching `query`, newest first.
+    pub fn list(&self, query: &ArchiveQuery) -> Vec<ArchiveEntry> {
+        let mut entries: Vec<ArchiveEntry> = self
+            .lock()
+            .values()
+            .filter(|entry| query.matches(entry))
+            .cloned()
+            .collect();
+        entries.sort_by_key(|entry| Reverse(entry.created_at));
+        entries.truncate(query.limit.unwrap_or(usize::MAX));
+        entries
+    }
+
+    pub fn get(&self, id: &str) -> Result<ArchiveEntry> {
+        match self.lock().get(id) {
+            Some(entry) => Ok(entry.clone()),
+            None => ReportNotFoundSnafu { id }.fail(),
+        }
+    }
+
+    /// An archived report with its file.
+    pub async fn file(&self, id: &str) -> Result<(ArchiveEntry, CachedReport)> {
+        let entry = self.get(id)?;
+        let report = CachedReport {
+            content_type: entry.content_type.clone(),
+            ext: entry.ext.clone(),
+            body: self.storage.get(id).await?,
+        };
+        Ok((entry, report))
+    }
+
+    /// Remove reports older than the maximum age, then the oldest ones while
+    /// the archive is larger than its maximum size.
+    pub async fn remove_expired(&self) {
+        let now = Utc::now();
+        let expired: Vec<String> = {
+            let mut entries = self.lock();
+            let mut oldest_first: Vec<&ArchiveEntry> = entries.values().collect();
+            oldest_first.sort_by_key(|entry| entry.created_at);
+            let mut total: u64 = oldest_first.iter().map(|entry| entry.size).sum();
+            let mut expired = vec![];
+            for entry in oldest_first {
+                let too_old = self.max_age.is_some_and(|max_age| {
+                    (now - entry.created_at).to_std().unwrap_or_default() >= max_age
+                });
+                let too_large = self.max_total_bytes.is_some_and(|max| total > max);
+                if !too_old && !too_large {
+                    break;
+                }
+                total -= entry.size;
+                expired.push(entry.id.clone());
+            }
+            for id in &expired {
+                entries.remove(id);
+            }
+            expired
+        };
+        for id in expired {
+            let removed = async {
+                self.storage.delete(&format!("{id}.json")).await?;
+                self.storage.delete(&id).await
+            };
+            match removed.await {
+                Ok(()) => tracing::debug!("removed archived report {}", id),
+                Err(e) => tracing::warn!("remove archived report {} error: {}", id, e),
+            }
+        }
+    }
+}
+
+/// Enforce the retention periodically until `shutdown` is cancelled.
+pub fn spawn_retention(archive: Arc<Archive>, shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RETENTION_SWEEP);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => archive.remove_expired().await,
+            }
+        }
+    });
+}
+
+/// Archive a report rendered for `user` in the background when the archive is
+/// enabled; failures are logged and do not fail the export, and shutdown
+/// waits for the write.
+pub(crate) fn record(
+    state: &ServerState,
+    user: &str,
+    theme: &str,
+    name: &str,
+    report: &CachedReport,
+) {
+    let Some(archive) = state.archive.clone() else {
+        return;
+    };
+    let in_flight = state.in_flight.enter();
+    let (user, theme, name) = (user.to_string(), theme.to_string(), name.to_string());
+    let report = report.clone();
+    tokio::spawn(async move {
+        let _in_flight = in_flight;
+        match archive.store(&user, &theme, &name, &report).await {
+            Ok(entry) => tracing::debug!("archived report {} as {}", name, entry.id),
+            Err(e) => tracing::error!("archive report {} error: {}", name, e),
+        }
+    });
+}
+
+fn archive(state: &ServerState) -> Result<Arc<Archive>> {
+    state
+        .archive
+        .clone()
+        .ok_or_else(|| eyre!("report archive is disabled").into())
+}
+
+/// `entry` if the caller may see it, reported as not found otherwise.
+fn visible(state: &ServerState, auth_info: &AuthInfo, entry: ArchiveEntry) -> Result<ArchiveEntry> {
+    if entry.user == auth_info.user_id || state.authorizer.archive_wide(auth_info) {
+        Ok(entry)
+    } else {
+        ReportNotFoundSnafu { id: entry.id }.fail()
+    }
+}
+
+/// List archived reports, limited to the caller's own ones unless an
+/// authorization rule grants `archive`.
+pub async fn list_reports(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    ValidatedQuery(mut query): ValidatedQuery<ArchiveQuery>,
+) -> Result<Json<Vec<ArchiveEntry>>> {
+    let archive = archive(&state)?;
+    if !state.authorizer.archive_wide(&auth_info) {
+        ensure!(
+            query
+                .user
+                .as_ref()
+                .is_none_or(|user| *user == auth_info.user_id),
+            ForbiddenSnafu {
+                reason: "reports of other users are not allowed",
+            }
+        );
+        query.user = Some(auth_info.user_id.clone());
+    }
+    Ok(Json(archive.list(&query)))
+}
+
+pub async fn report_info(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    Path(id): Path<String>,
+) -> Result<Json<ArchiveEntry>> {
+    let entry = archive(&state)?.get(&id)?;
+    Ok(Json(visible(&state, &auth_info, entry)?))
+}
+
+pub async fn report_file(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    Path(id): Path<String>,
+) -> Result<Response> {
+    let archive = archive(&state)?;
+    let entry = visible(&state, &auth_info, archive.get(&id)?)?;
+    let (_, report) = archive.file(&entry.id).await?;
+    Ok(report_response(&entry.name, &report))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        process,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use axum::body::Bytes;
+
+    use super::*;
+    use crate::{
+        error::Error,
+        s3::{self, S3Client},
+    };
+
+    fn config(max_age_secs: Option<u64>, max_total_bytes: Option<u64>) -> ArchiveConfig {
+        static DIRS: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "archive-test-{}-{}",
+            process::id(),
+            DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        ArchiveConfig {
+            dir: dir.display().to_string(),
+            s3: None,
+            max_age_secs,
+            max_total_bytes,
+        }
+    }
+
+    fn pdf() -> CachedReport {
+        CachedReport {
+            content_type: "application/pdf".to_string(),
+            ext: Some(".pdf".to_string()),
+            body: Bytes::from_static(b"%PDF"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_store_list_and_reload() {
+        let config = config(None, None);
+        let archive = Archive::new(&config).unwrap();
+        let weekly = archive
+            .store("alice", "default", "weekly", &pdf())
+            .await
+            .unwrap();
+        archive
+            .store("bob", "internal", "monthly", &pdf())
+            .await
+            .unwrap();
+        assert_eq!(weekly.size, 4);
+        assert_eq!(weekly.sha256, format!("{:x}", Sha256::digest(b"%PDF")));
+
+        let all = archive.list(&ArchiveQuery::default());
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].name, "monthly");
+        let query = ArchiveQuery {
+            user: Some("alice".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(archive.list(&query).len(), 1);
+        let query = ArchiveQuery {
+            name: Some("month".to_string()),
+            since: Some(weekly.created_at),
+            ..Default::default()
+        };
+        assert_eq!(archive.list(&query)[0].user, "bob");
+        let query = ArchiveQuery {
+            until: Some(weekly.created_at),
+            ..Default::default()
+        };
+        assert!(archive.list(&query).is_empty());
+
+        let reloaded = Archive::new(&config).unwrap();
+        reloaded.load().await.unwrap();
+        let (entry, report) = reloaded.file(&weekly.id).await.unwrap();
+        assert_eq!(entry.name, "weekly");
+        assert_eq!(report.body, "%PDF");
+        assert!(matches!(
+            reloaded.get("missing"),
+            Err(Error::ReportNotFound { .. })
+        ));
+        std::fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_archive_retention_by_age() {
+        let config = config(Some(0), None);
+        let archive = Archive::new(&config).unwrap();
+        let entry = archive
+            .store("alice", "default", "weekly", &pdf())
+            .await
+            .unwrap();
+        archive.remove_expired().await;
+        assert!(archive.get(&entry.id).is_err());
+        assert_eq!(std::fs::read_dir(&config.dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_archive_retention_by_size() {
+        let config = config(Some(3600), Some(8));
+        let archive = Archive::new(&config).unwrap();
+        let mut ids = vec![];
+        for name in ["a", "b", "c"] {
+            let entry = archive
+                .store("alice", "default", name, &pdf())
+                .await
+                .unwrap();
+            ids.push(entry.id);
+        }
+        archive.remove_expired().await;
+        assert!(archive.get(&ids[0]).is_err());
+        assert!(archive.get(&ids[1]).is_ok());
+        assert!(archive.get(&ids[2]).is_ok());
+        std::fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_archive_in_bucket() {
+        let (endpoint, objects) = s3::tests::stand_in().await;
+        let storage = || {
+            Storage::S3(Box::new(
+                S3Client::new(s3::tests::settings(&endpoint, "archive/")).unwrap(),
+            ))
+        };
+        let config = ArchiveConfig {
+            dir: String::new(),
+            s3: None,
+            max_age_secs: None,
+            max_total_bytes: Some(4),
+        };
+        let archive = Archive::with_storage(storage(), &config);
+        let first = archive
+            .store("alice", "default", "weekly", &pdf())
+            .await
+            .unwrap();
+        let second = archive
+            .store("alice", "default", "weekly", &pdf())
+            .await
+            .unwrap();
+        assert_eq!(objects.lock().unwrap().len(), 4);
+        assert!(
+            objects
+                .lock()
+                .unwrap()
+                .contains_key(&format!("archive/{}.json", first.id))
+        );
+
+        let reloaded = Archive::with_storage(storage(), &config);
+        reloaded.load().await.unwrap();
+        assert_eq!(reloaded.list(&ArchiveQuery::default()).len(), 2);
+        let (_, report) = reloaded.file(&first.id).await.unwrap();
+        assert_eq!(report.body, "%PDF");
+
+        reloaded.remove_expired().await;
+        assert!(reloaded.get(&first.id).is_err());
+        assert!(reloaded.get(&second.id).is_ok());
+        assert_eq!(objects.lock().unwrap().len(), 2);
+    }
+}
@@ -1,19 +1,228 @@
-use axum::{extract::Request, middleware::Next, response::Response};
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::header::AUTHORIZATION,
+    middleware::Next,
+    response::Response,
+};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation, decode, decode_header,
+    jwk::{Jwk, JwkSet},
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
 
-use crate::error::Error as ApiError;
+use crate::{
+    config::{AuthConfig, JwtAlgorithm, JwtConfig},
+    error::{ConfigParseSnafu, Error as ApiError, FileIoSnafu, JwtKeySnafu, Result},
+    metrics::metrics,
+    token_review::TokenReviewer,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthInfo {
     pub user_id: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// Verifies bearer tokens against the configured static tokens, JWT keys and
+/// Kubernetes `TokenReview`, in that order.
+pub struct Authenticator {
+    /// Hex encoded sha256 of a token to the identity it belongs to.
+    tokens: HashMap<String, AuthInfo>,
+    jwt: Option<JwtVerifier>,
+    token_review: Option<TokenReviewer>,
+}
+
+struct JwtVerifier {
+    keys: JwtKeys,
+    validation: Validation,
+    user_claim: String,
+}
+
+enum JwtKeys {
+    Single(DecodingKey),
+    /// Keys of a JWKS file, selected by the token's `kid`.
+    Set(Vec<(Option<String>, DecodingKey)>),
+}
+
+pub fn sha256_hex(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Result<Self> {
+        let tokens = config
+            .tokens
+            .iter()
+            .map(|t| {
+                let auth_info = AuthInfo {
+                    user_id: t.user_id.clone(),
+                    groups: t.groups.clone(),
+                };
+                (t.sha256.to_lowercase(), auth_info)
+            })
+            .collect();
+        let jwt = config.jwt.as_ref().map(JwtVerifier::new).transpose()?;
+        let token_review = config
+            .token_review
+            .as_ref()
+            .map(TokenReviewer::new)
+            .transpose()?;
+        if config.tokens.is_empty() && jwt.is_none() && token_review.is_none() {
+            tracing::warn!("no token verifier is configured, every /api request will be rejected");
+        }
+        Ok(Self {
+            tokens,
+            jwt,
+            token_review,
+        })
+    }
+
+    pub async fn verify(&self, token: &str) -> Result<AuthInfo> {
+        if let Some(auth_info) = self.tokens.get(&sha256_hex(token)) {
+            return Ok(auth_info.clone());
+        }
+        if let Some(jwt) = &self.jwt {
+            match jwt.verify(token) {
+                Ok(auth_info) => return Ok(auth_info),
+                Err(e) if self.token_review.is_none() => return Err(e),
+                Err(_) => {}
+            }
+        }
+        if let Some(token_review) = &self.token_review {
+            return token_review.verify(token).await;
+        }
+        Err(ApiError::InvalidToken)
+    }
+}
+
+impl JwtVerifier {
+    fn new(config: &JwtConfig) -> Result<Self> {
+        let algorithm = match config.algorithm {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+        };
+        let keys = if let Some(path) = &config.jwks_file {
+            let json = std::fs::read(path).context(FileIoSnafu)?;
+            let set: JwkSet = serde_json::from_slice(&json).context(ConfigParseSnafu)?;
+            let keys = set
+                .keys
+                .iter()
+                .map(|jwk: &Jwk| {
+                    let key = DecodingKey::from_jwk(jwk).context(JwtKeySnafu)?;
+                    Ok((jwk.common.key_id.clone(), key))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            JwtKeys::Set(keys)
+        } else if let Some(path) = &config.public_key_file {
+            let pem = std::fs::read(path).context(FileIoSnafu)?;
+            let key = match algorithm {
+                Algorithm::RS256 => DecodingKey::from_rsa_pem(&pem).context(JwtKeySnafu)?,
+                _ => DecodingKey::from_secret(&pem),
+            };
+            JwtKeys::Single(key)
+        } else if let Some(secret) = &config.secret {
+            JwtKeys::Single(DecodingKey::from_secret(secret.as_bytes()))
+        } else {
+            return Err(ApiError::InvalidInput {
+                reason: "jwt needs one of secret, public_key_file or jwks_file".to_string(),
+            });
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = config.leeway_secs;
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&config.audience);
+        }
+        Ok(Self {
+            keys,
+            validation,
+            user_claim: config.user_claim.clone(),
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<AuthInfo> {
+        let key = match &self.keys {
+            JwtKeys::Single(key) => key,
+            JwtKeys::Set(keys) => {
+                let kid = decode_header(token)
+                    .map_err(|e| {
+                        tracing::debug!("invalid jwt header: {}", e);
+                        ApiError::InvalidToken
+                    })?
+                    .kid;
+                keys.iter()
+                    .find(|(id, _)| kid.is_none() || id == &kid)
+                    .map(|(_, key)| key)
+                    .ok_or(ApiError::InvalidToken)?
+            }
+        };
+        let claims = decode::<serde_json::Map<String, serde_json::Value>>(
+            token,
+            key,
+            &self.validation,
+        )
+        .map_err(|e| {
+            tracing::debug!("jwt rejected: {}", e);
+            ApiError::InvalidToken
+        })?
+        .claims;
+        let user_id = claims
+            .get(&self.user_claim)
+            .and_then(|v| v.as_str())
+            .ok_or(ApiError::InvalidToken)?;
+        let groups = claims
+            .get("groups")
+            .and_then(|v| v.as_array())
+            .map(|groups| {
+                groups
+                    .iter()
+                    .filter_map(|g| g.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(AuthInfo {
+            user_id: user_id.to_string(),
+            groups,
+        })
+    }
 }
 
 // 中间件：验证 token
-pub async fn simple_token_auth(req: Request, next: Next) -> Result<Response, ApiError> {
-    req.headers()
-        .get("Authorization")
+fn bearer_token(req: &Request) -> Result<String> {
+    let header = req
+        .headers()
+        .get(AUTHORIZATION)
         .and_then(|v| v.to_str().ok())
         .ok_or(ApiError::MissingAuth)?;
+    let token = header
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .ok_or(ApiError::InvalidToken)?;
+    Ok(token.to_string())
+}
+
+pub async fn simple_token_auth(
+    State(authenticator): State<Arc<Authenticator>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let result = match bearer_token(&req) {
+        Ok(token) => authenticator.verify(&token).await,
+        Err(e) => Err(e),
+    };
+    let auth_info = result.inspect_err(|e| metrics().auth_failed(e.kind()))?;
+    req.extensions_mut().insert(auth_info);
 
     Ok(next.run(req).await)
 }
@@ -21,76 +230,206 @@ pub async fn simple_token_auth(req: Request, next: Next) -> Result<Response, Api
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::StaticToken;
     use axum::{
         body::Body,
         http::{Request, StatusCode},
         middleware,
         routing::get,
-        Router,
+        Extension, Router,
     };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
     use tower::ServiceExt;
 
-    async fn test_handler() -> &'static str {
-        "success"
+    async fn test_handler(Extension(auth_info): Extension<AuthInfo>) -> String {
+        auth_info.user_id
     }
 
-    #[tokio::test]
-    async fn test_auth_with_valid_token() {
-        let app = Router::new()
+    fn app(config: AuthConfig) -> Router {
+        let authenticator = Arc::new(Authenticator::new(&config).unwrap());
+        Router::new()
             .route("/protected", get(test_handler))
-            .layer(middleware::from_fn(simple_token_auth));
+            .layer(middleware::from_fn_with_state(authenticator, simple_token_auth))
+    }
+
+    fn static_token_config() -> AuthConfig {
+        AuthConfig {
+            tokens: vec![StaticToken {
+                sha256: sha256_hex("valid_token"),
+                user_id: "alice".to_string(),
+                groups: vec![],
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn jwt_config() -> JwtConfig {
+        JwtConfig {
+            algorithm: JwtAlgorithm::HS256,
+            secret: Some("secret".to_string()),
+            public_key_file: None,
+            jwks_file: None,
+            issuer: Some("kube-eye".to_string()),
+            audience: vec!["export".to_string()],
+            user_claim: "sub".to_string(),
+            leeway_secs: 0,
+        }
+    }
+
+    fn jwt(claims: serde_json::Value, header: Header, secret: &[u8]) -> String {
+        encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn exp() -> u64 {
+        jsonwebtoken::get_current_timestamp() + 600
+    }
 
-        let request = Request::builder()
-            .uri("/protected")
-            .header("Authorization", "Bearer valid_token")
-            .body(Body::empty())
+    async fn call(app: Router, authorization: Option<&str>) -> (StatusCode, String) {
+        let mut request = Request::builder().uri("/protected");
+        if let Some(authorization) = authorization {
+            request = request.header("Authorization", authorization);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
             .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
 
-        let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
+    #[tokio::test]
+    async fn test_auth_with_valid_token() {
+        let (status, body) = call(app(static_token_config()), Some("Bearer valid_token")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "alice");
+    }
+
+    #[tokio::test]
+    async fn test_auth_with_unknown_token() {
+        let (status, _) = call(app(static_token_config()), Some("Bearer other_token")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
     }
 
     #[tokio::test]
     async fn test_auth_without_token() {
-        let app = Router::new()
-            .route("/protected", get(test_handler))
-            .layer(middleware::from_fn(simple_token_auth));
+        let (status, _) = call(app(static_token_config()), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
 
-        let request = Request::builder()
-            .uri("/protected")
-            .body(Body::empty())
-            .unwrap();
+    #[tokio::test]
+    async fn test_auth_with_empty_header() {
+        let (status, _) = call(app(static_token_config()), Some("")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_auth_without_verifiers_rejects() {
+        let (status, _) = call(app(AuthConfig::default()), Some("Bearer valid_token")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
 
-        let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    #[tokio::test]
+    async fn test_auth_with_valid_jwt() {
+        let config = AuthConfig {
+            jwt: Some(jwt_config()),
+            ..Default::default()
+        };
+        let token = jwt(
+            json!({ "sub": "bob", "iss": "kube-eye", "aud": "export", "exp": exp() }),
+            Header::default(),
+            b"secret",
+        );
+        let (status, body) = call(app(config), Some(&format!("Bearer {token}"))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "bob");
     }
 
     #[tokio::test]
-    async fn test_auth_with_empty_header() {
-        let app = Router::new()
-            .route("/protected", get(test_handler))
-            .layer(middleware::from_fn(simple_token_auth));
+    async fn test_auth_rejects_invalid_jwt_claims() {
+        let claims = [
+            json!({ "sub": "bob", "iss": "other", "aud": "export", "exp": exp() }),
+            json!({ "sub": "bob", "iss": "kube-eye", "aud": "other", "exp": exp() }),
+            json!({ "sub": "bob", "iss": "kube-eye", "aud": "export", "exp": 1 }),
+            json!({ "iss": "kube-eye", "aud": "export", "exp": exp() }),
+        ];
+        for claims in claims {
+            let config = AuthConfig {
+                jwt: Some(jwt_config()),
+                ..Default::default()
+            };
+            let token = jwt(claims, Header::default(), b"secret");
+            let (status, _) = call(app(config), Some(&format!("Bearer {token}"))).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+    }
 
-        let request = Request::builder()
-            .uri("/protected")
-            .header("Authorization", "")
-            .body(Body::empty())
-            .unwrap();
+    #[tokio::test]
+    async fn test_auth_rejects_jwt_with_wrong_secret() {
+        let config = AuthConfig {
+            jwt: Some(jwt_config()),
+            ..Default::default()
+        };
+        let token = jwt(
+            json!({ "sub": "bob", "iss": "kube-eye", "aud": "export", "exp": exp() }),
+            Header::default(),
+            b"other",
+        );
+        let (status, _) = call(app(config), Some(&format!("Bearer {token}"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_auth_with_jwks_file() {
+        let jwks = json!({
+            "keys": [{ "kty": "oct", "kid": "k1", "k": "c2VjcmV0", "alg": "HS256" }]
+        });
+        let path = std::env::temp_dir().join(format!("jwks-{}.json", std::process::id()));
+        std::fs::write(&path, jwks.to_string()).unwrap();
+        let config = AuthConfig {
+            jwt: Some(JwtConfig {
+                secret: None,
+                jwks_file: Some(path.display().to_string()),
+                ..jwt_config()
+            }),
+            ..Default::default()
+        };
+        let header = Header {
+            kid: Some("k1".to_string()),
+            ..Default::default()
+        };
+        let token = jwt(
+            json!({ "sub": "carol", "iss": "kube-eye", "aud": "export", "exp": exp() }),
+            header,
+            b"secret",
+        );
+        let (status, body) = call(app(config), Some(&format!("Bearer {token}"))).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "carol");
+    }
 
-        let response = app.oneshot(request).await.unwrap();
-        // Empty authorization header should still pass through the middleware
-        // since we only check if the header exists
-        assert_eq!(response.status(), StatusCode::OK);
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
     }
 
     #[test]
     fn test_auth_info_serialization() {
         let auth_info = AuthInfo {
             user_id: "user123".to_string(),
+            groups: vec!["admins".to_string()],
         };
 
         let json = serde_json::to_string(&auth_info).unwrap();
         assert!(json.contains("user123"));
+        assert!(json.contains("admins"));
     }
 
     #[test]
@@ -98,12 +437,14 @@ mod tests {
         let json = r#"{"user_id": "user456"}"#;
         let auth_info: AuthInfo = serde_json::from_str(json).unwrap();
         assert_eq!(auth_info.user_id, "user456");
+        assert!(auth_info.groups.is_empty());
     }
 
     #[test]
     fn test_auth_info_clone() {
         let auth_info = AuthInfo {
             user_id: "user789".to_string(),
+            groups: vec![],
         };
         let cloned = auth_info.clone();
         assert_eq!(auth_info.user_id, cloned.user_id);
@@ -0,0 +1,174 @@
+use crate::{
+    auth::AuthInfo,
+    config::{AuthorizationConfig, AuthorizationRule},
+    error::{ForbiddenSnafu, Result},
+    metrics::metrics,
+};
+
+/// Matches everything in a rule's user, group or theme list.
+const WILDCARD: &str = "*";
+
+/// Decides which themes and resources an authenticated caller may use.
+///
+/// Without rules every authenticated caller is allowed everything; once a
+/// rule exists, anything not granted by a matching rule is denied.
+pub struct Authorizer {
+    rules: Vec<AuthorizationRule>,
+}
+
+impl Authorizer {
+    pub fn new(config: &AuthorizationConfig) -> Self {
+        Self {
+            rules: config.rules.clone(),
+        }
+    }
+
+    pub fn authorize_theme(&self, auth_info: &AuthInfo, theme: &str) -> Result<()> {
+        self.authorize(auth_info, |rule| {
+            rule.themes.iter().any(|t| t == WILDCARD || t == theme)
+        })
+        .map_err(|_| {
+            ForbiddenSnafu {
+                reason: format!("theme {theme} is not allowed"),
+            }
+            .build()
+        })
+    }
+
+    pub fn authorize_client_config(&self, auth_info: &AuthInfo) -> Result<()> {
+        self.authorize(auth_info, |rule| rule.client_config)
+            .map_err(|_| {
+                ForbiddenSnafu {
+                    reason: "client config is not allowed",
+                }
+                .build()
+            })
+    }
+
+    /// Whether the caller may see archived reports of every user, not only
+    /// their own.
+    pub fn archive_wide(&self, auth_info: &AuthInfo) -> bool {
+        self.grants(auth_info, |rule| rule.archive)
+    }
+
+    fn authorize(
+        &self,
+        auth_info: &AuthInfo,
+        grants: impl Fn(&AuthorizationRule) -> bool,
+    ) -> Result<(), ()> {
+        if self.grants(auth_info, grants) {
+            Ok(())
+        } else {
+            tracing::info!("denied request of user {}", auth_info.user_id);
+            metrics().auth_failed("forbidden");
+            Err(())
+        }
+    }
+
+    fn grants(&self, auth_info: &AuthInfo, grants: impl Fn(&AuthorizationRule) -> bool) -> bool {
+        self.rules.is_empty()
+            || self
+                .rules
+                .iter()
+                .filter(|rule| matches(rule, auth_info))
+                .any(grants)
+    }
+}
+
+fn matches(rule: &AuthorizationRule, auth_info: &AuthInfo) -> bool {
+    rule.users
+        .iter()
+        .any(|u| u == WILDCARD || *u == auth_info.user_id)
+        || rule
+            .groups
+            .iter()
+            .any(|g| g == WILDCARD || auth_info.groups.contains(g))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn auth_info(user_id: &str, groups: &[&str]) -> AuthInfo {
+        AuthInfo {
+            user_id: user_id.to_string(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+
+    fn authorizer() -> Authorizer {
+        Authorizer::new(&AuthorizationConfig {
+            rules: vec![
+                AuthorizationRule {
+                    users: vec!["alice".to_string()],
+                    groups: vec![],
+                    themes: vec![WILDCARD.to_string()],
+                    client_config: true,
+                    archive: true,
+                },
+                AuthorizationRule {
+                    users: vec![],
+                    groups: vec!["tenants".to_string()],
+                    themes: vec!["default".to_string()],
+                    client_config: false,
+                    archive: false,
+                },
+            ],
+        })
+    }
+
+    #[test]
+    fn test_authorize_without_rules_allows_everything() {
+        let authorizer = Authorizer::new(&AuthorizationConfig::default());
+        let bob = auth_info("bob", &[]);
+        assert!(authorizer.authorize_theme(&bob, "internal").is_ok());
+        assert!(authorizer.authorize_client_config(&bob).is_ok());
+    }
+
+    #[test]
+    fn test_authorize_theme_by_user_and_group() {
+        let authorizer = authorizer();
+        let alice = auth_info("alice", &[]);
+        let tenant = auth_info("bob", &["tenants"]);
+        assert!(authorizer.authorize_theme(&alice, "internal").is_ok());
+        assert!(authorizer.authorize_theme(&tenant, "default").is_ok());
+        assert!(matches!(
+            authorizer.authorize_theme(&tenant, "internal"),
+            Err(Error::Forbidden { .. })
+        ));
+    }
+
+    #[test]
+    fn test_authorize_unknown_user_denied() {
+        let authorizer = authorizer();
+        let carol = auth_info("carol", &["others"]);
+        assert!(authorizer.authorize_theme(&carol, "default").is_err());
+        assert!(authorizer.authorize_client_config(&carol).is_err());
+    }
+
+    #[test]
+    fn test_authorize_client_config() {
+        let authorizer = authorizer();
+        assert!(
+            authorizer
+                .authorize_client_config(&auth_info("alice", &[]))
+                .is_ok()
+        );
+        assert!(
+            authorizer
+                .authorize_client_config(&auth_info("bob", &["tenants"]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_archive_wide() {
+        let authorizer = authorizer();
+        assert!(authorizer.archive_wide(&auth_info("alice", &[])));
+        assert!(!authorizer.archive_wide(&auth_info("bob", &["tenants"])));
+        assert!(
+            Authorizer::new(&AuthorizationConfig::default()).archive_wide(&auth_info("bob", &[]))
+        );
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    collections::HashSet,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use axum::{
+    Extension,
+    body::{Body, Bytes},
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, ensure};
+use tokio::{
+    sync::{Semaphore, mpsc},
+    task::JoinSet,
+};
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    auth::AuthInfo,
+    cache::CachedReport,
+    error::{Error, FileIoSnafu, InvalidInputSnafu, JsonSerializeSnafu, Result, ZipSnafu},
+    extractor::ValidatedJson,
+    rate_limit,
+    render_pool::retry_busy,
+    server::{ReportRequest, ServerState, attachment_headers, encoded_filename, render_cached},
+    typst_lib::Diagnostic,
+};
+
+/// Most reports a single batch may ask for.
+const MAX_BATCH_REPORTS: usize = 100;
+
+/// Entry listing the reports that failed, present only if any did.
+const ERRORS_FILE: &str = "errors.json";
+
+/// How long a report waits for room in the render queue or in the quota of
+/// its user before it is listed as failed.
+const ITEM_WAIT: Duration = Duration::from_secs(60);
+
+/// Body of `POST /api/report/batch`.
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    /// Download name of the ZIP archive.
+    pub name: String,
+    pub reports: Vec<ReportRequest>,
+}
+
+/// A report of a batch that could not be rendered.
+#[derive(Debug, Serialize)]
+pub struct BatchError {
+    /// Position of the report in the request.
+    pub index: usize,
+    pub name: String,
+    pub error: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl BatchError {
+    fn new(index: usize, name: String, error: Error) -> Self {
+        let message = error.to_string();
+        let diagnostics = match error {
+            Error::TypstCompile { diagnostics } => diagnostics,
+            _ => vec![],
+        };
+        Self {
+            index,
+            name,
+            error: message,
+            diagnostics,
+        }
+    }
+}
+
+/// Sink of a ZIP archive streamed while it is written.
+///
+/// `ZipWriter` seeks back into an entry to patch its header and flushes once
+/// the entry is done, so written bytes are held until a flush and then handed
+/// out as one chunk; seeking never reaches into chunks already handed out.
+#[derive(Clone, Default)]
+struct ChunkWriter {
+    chunks: Arc<Mutex<Chunks>>,
+}
+
+#[derive(Default)]
+struct Chunks {
+    /// Bytes already handed out.
+    sent: u64,
+    pending: Vec<u8>,
+    /// Write position within `pending`.
+    position: usize,
+    ready: Vec<Bytes>,
+}
+
+impl ChunkWriter {
+    fn lock(&self) -> MutexGuard<'_, Chunks> {
+        self.chunks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Chunks flushed since the last call.
+    fn take_ready(&self) -> Vec<Bytes> {
+        std::mem::take(&mut self.lock().ready)
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut chunks = self.lock();
+        let end = chunks.position + buf.len();
+        if end > chunks.pending.len() {
+            chunks.pending.resize(end, 0);
+        }
+        let start = chunks.position;
+        chunks.pending[start..end].copy_from_slice(buf);
+        chunks.position = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut chunks = self.lock();
+        // A header may still be patched while the position is behind the end.
+        if chunks.pending.is_empty() || chunks.position != chunks.pending.len() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut chunks.pending);
+        chunks.sent += pending.len() as u64;
+        chunks.position = 0;
+        chunks.ready.push(pending.into());
+        Ok(())
+    }
+}
+
+/// Never read from; only required by `ZipWriter::set_flush_on_finish_file`.
+impl Read for ChunkWriter {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cannot read streamed zip data",
+        ))
+    }
+}
+
+impl Seek for ChunkWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut chunks = self.lock();
+        let sent = chunks.sent as i128;
+        let target = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::End(offset) => sent + chunks.pending.len() as i128 + offset as i128,
+            SeekFrom::Current(offset) => sent + chunks.position as i128 + offset as i128,
+        };
+        if target < sent || target > sent + chunks.pending.len() as i128 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot seek into streamed zip data",
+            ));
+        }
+        chunks.position = (target - sent) as usize;
+        Ok(target as u64)
+    }
+}
+
+/// `{stem}{ext}`, or `{stem}-2{ext}` and so on when already taken.
+fn unique_name(taken: &mut HashSet<String>, stem: &str, ext: &str) -> String {
+    let mut name = format!("{stem}{ext}");
+    let mut suffix = 1;
+    while !taken.insert(name.clone()) {
+        suffix += 1;
+        name = format!("{stem}-{suffix}{ext}");
+    }
+    name
+}
+
+fn write_entry(zip: &mut ZipWriter<ChunkWriter>, name: &str, body: &[u8]) -> Result<()> {
+    zip.start_file(name, SimpleFileOptions::default())
+        .context(ZipSnafu)?;
+    zip.write_all(body).context(FileIoSnafu)
+}
+
+/// Render one report of a batch like `POST /api/report`, counted against the
+/// quota of the user as a request of its own and waiting a while for room
+/// instead of failing at once.
+async fn render_item(
+    state: &ServerState,
+    auth_info: &AuthInfo,
+    report: ReportRequest,
+) -> Result<Arc<CachedReport>> {
+    let request = report.into_render()?;
+    state
+        .authorizer
+        .authorize_theme(auth_info, &request.theme)?;
+    let key = rate_limit::user_key(&auth_info.user_id);
+    retry_busy(ITEM_WAIT, || async {
+        let _permit = match &state.rate_limiter {
+            Some(limiter) => Some(limiter.check(&key)?),
+            None => None,
+        };
+        let (report, _) = render_cached(state, &auth_info.user_id, request.clone()).await?;
+        Ok(report)
+    })
+    .await
+}
+
+/// Hand the chunks written so far to the response, returning false once the
+/// client is gone.
+async fn send_ready(writer: &ChunkWriter, sender: &mpsc::Sender<io::Result<Bytes>>) -> bool {
+    for chunk in writer.take_ready() {
+        if sender.send(Ok(chunk)).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Render `reports`, at most as many at once as the pool has workers, and
+/// add each to the ZIP as soon as it is done.
+async fn stream_batch(
+    state: ServerState,
+    auth_info: AuthInfo,
+    reports: Vec<ReportRequest>,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+) {
+    let _in_flight = state.in_flight.enter();
+    let permits = Arc::new(Semaphore::new(state.render_pool.workers()));
+    let auth_info = Arc::new(auth_info);
+    let mut renders = JoinSet::new();
+    for (index, report) in reports.into_iter().enumerate() {
+        let state = state.clone();
+        let auth_info = Arc::clone(&auth_info);
+        let permits = Arc::clone(&permits);
+        renders.spawn(async move {
+            let name = report.name.clone();
+            let _permit = permits.acquire_owned().await;
+            (index, name, render_item(&state, &auth_info, report).await)
+        });
+    }
+
+    let writer = ChunkWriter::default();
+    let mut zip = ZipWriter::new(writer.clone());
+    zip.set_flush_on_finish_file(true);
+    let mut names = HashSet::new();
+    let mut errors = vec![];
+    while let Some(joined) = renders.join_next().await {
+        let (index, name, result) = match joined {
+            Ok(rendered) => rendered,
+            Err(e) => {
+                tracing::error!("batch render task failed: {}", e);
+                continue;
+            }
+        };
+        let written = match result {
+            Ok(report) => {
+                let ext = report.ext.as_deref().unwrap_or(".json");
+                let file = unique_name(&mut names, &encoded_filename(&name), ext);
+                write_entry(&mut zip, &file, &report.body)
+            }
+            Err(e) => {
+                errors.push(BatchError::new(index, name, e));
+                Ok(())
+            }
+        };
+        if let Err(e) = written {
+            tracing::error!("write batch zip error: {}", e);
+            let _ = sender.send(Err(io::Error::other(e.to_string()))).await;
+            return;
+        }
+        if !send_ready(&writer, &sender).await {
+            tracing::info!("batch download cancelled by the client");
+            return;
+        }
+    }
+
+    let finished = (|| {
+        if !errors.is_empty() {
+            errors.sort_by_key(|error| error.index);
+            let json = serde_json::to_vec_pretty(&errors).context(JsonSerializeSnafu)?;
+            write_entry(&mut zip, ERRORS_FILE, &json)?;
+        }
+        zip.finish().context(ZipSnafu)?.flush().context(FileIoSnafu)
+    })();
+    if let Err(e) = finished {
+        tracing::error!("finish batch zip error: {}", e);
+        let _ = sender.send(Err(io::Error::other(e.to_string()))).await;
+        return;
+    }
+    send_ready(&writer, &sender).await;
+}
+
+/// Render many reports into one ZIP archive, streamed as they finish; reports
+/// that fail are listed in its `errors.json`.
+#[tracing::instrument(name = "report_batch", skip(state, payload), fields(user = %auth_info.user_id))]
+pub async fn report_batch(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    ValidatedJson(payload): ValidatedJson<BatchRequest>,
+) -> Result<Response> {
+    let BatchRequest { name, reports } = payload;
+    ensure!(
+        !reports.is_empty() && reports.len() <= MAX_BATCH_REPORTS,
+        InvalidInputSnafu {
+            reason: format!("A batch holds 1 to {MAX_BATCH_REPORTS} reports"),
+        }
+    );
+    let (sender, receiver) = mpsc::channel(4);
+    // Every report takes a render slot of its own, see `render_item`.
+    tokio::spawn(stream_batch(state, auth_info, reports, sender));
+    let body = Body::from_stream(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    }));
+    Ok((attachment_headers(&name, "application/zip", ".zip"), body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use zip::ZipArchive;
+
+    use super::*;
+
+    #[test]
+    fn test_chunk_writer_streams_entries() {
+        let writer = ChunkWriter::default();
+        let mut zip = ZipWriter::new(writer.clone());
+        zip.set_flush_on_finish_file(true);
+        let mut streamed = vec![];
+        for (name, body) in [("a.pdf", "first"), ("b.pdf", "second")] {
+            write_entry(&mut zip, name, body.as_bytes()).unwrap();
+            streamed.extend(writer.take_ready());
+        }
+        // The first entry is handed out once the second one starts.
+        assert!(!streamed.is_empty());
+        zip.finish().unwrap().flush().unwrap();
+        streamed.extend(writer.take_ready());
+
+        let bytes: Vec<u8> = streamed.concat();
+        let mut archive = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut body = String::new();
+        archive
+            .by_name("b.pdf")
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "second");
+        assert_eq!(archive.len(), 2);
+    }
+
+    #[test]
+    fn test_chunk_writer_rejects_seeking_into_sent_data() {
+        let mut writer = ChunkWriter::default();
+        writer.write_all(b"abc").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.take_ready(), [Bytes::from("abc")]);
+        assert_eq!(writer.stream_position().unwrap(), 3);
+        assert!(writer.seek(SeekFrom::Start(1)).is_err());
+    }
+
+    #[test]
+    fn test_unique_name() {
+        let mut taken = HashSet::new();
+        assert_eq!(unique_name(&mut taken, "a", ".pdf"), "a.pdf");
+        assert_eq!(unique_name(&mut taken, "a", ".pdf"), "a-2.pdf");
+        assert_eq!(unique_name(&mut taken, "a", ".png"), "a.png");
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::body::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+use crate::{
+    config::CacheConfig,
+    error::{Error, Result},
+    metrics::metrics,
+};
+
+/// A packed report response, without the download name.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CachedReport {
+    pub content_type: String,
+    /// Extension of the attachment, e.g. `.pdf`; sent inline when unset.
+    pub ext: Option<String>,
+    #[serde(skip)]
+    pub body: Bytes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Rendered for this request.
+    Miss,
+    /// Served from the cache or by a concurrent identical render.
+    Hit,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Miss => "miss",
+            CacheStatus::Hit => "hit",
+        }
+    }
+}
+
+/// Hex encoded sha256 of `parts`, each length prefixed so that moving bytes
+/// between parts changes the key.
+pub fn cache_key<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Rendered reports by content hash, in a memory LRU backed by an optional
+/// directory; identical concurrent renders are coalesced.
+pub struct RenderCache {
+    ttl: Duration,
+    max_memory_bytes: usize,
+    memory: Mutex<Memory>,
+    disk: Option<Disk>,
+    pending: Mutex<HashMap<String, Arc<OnceCell<Arc<CachedReport>>>>>,
+}
+
+#[derive(Default)]
+struct Memory {
+    entries: HashMap<String, MemoryEntry>,
+    /// Keys by last use, least recent first.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+}
+
+struct MemoryEntry {
+    report: Arc<CachedReport>,
+    stored: Instant,
+    used: u64,
+}
+
+struct Disk {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<DiskIndex>,
+}
+
+/// Entries in the cache directory, tracked so that pruning never rescans it.
+#[derive(Default)]
+struct DiskIndex {
+    entries: HashMap<String, (SystemTime, u64)>,
+    /// Keys by modification time, oldest first.
+    order: BTreeSet<(SystemTime, String)>,
+    bytes: u64,
+}
+
+impl RenderCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_memory_bytes: config.max_memory_bytes,
+            memory: Mutex::default(),
+            disk: config
+                .dir
+                .as_ref()
+                .map(|dir| Disk::open(PathBuf::from(dir), config.max_disk_bytes)),
+            pending: Mutex::default(),
+        }
+    }
+
+    /// The report of `key`, rendered with `render` unless cached or already
+    /// being rendered by another request.
+    pub async fn get_or_render<F, Fut>(
+        &self,
+        key: &str,
+        render: F,
+    ) -> Result<(Arc<CachedReport>, CacheStatus)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<CachedReport>>,
+    {
+        if let Some(report) = self.get(key).await {
+            metrics().cache_lookup(CacheStatus::Hit);
+            return Ok((report, CacheStatus::Hit));
+        }
+        let cell = Arc::clone(
+            self.pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(key.to_string())
+                .or_default(),
+        );
+        let mut status = CacheStatus::Hit;
+        // A failed or cancelled render leaves the cell empty for the next waiter.
+        let result = cell
+            .get_or_try_init(|| async {
+                status = CacheStatus::Miss;
+                let report = Arc::new(render().await?);
+                self.insert(key, Arc::clone(&report)).await;
+                Ok::<_, Error>(report)
+            })
+            .await
+            .cloned();
+        {
+            let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            if pending
+                .get(key)
+                .is_some_and(|other| Arc::ptr_eq(other, &cell))
+            {
+                pending.remove(key);
+            }
+        }
+        let report = result?;
+        metrics().cache_lookup(status);
+        Ok((report, status))
+    }
+
+    async fn get(&self, key: &str) -> Option<Arc<CachedReport>> {
+        if let Some(report) = self.lock_memory().get(key, self.ttl) {
+            return Some(report);
+        }
+        let disk = self.disk.as_ref()?;
+        let (dir, key_owned, ttl) = (disk.dir.clone(), key.to_string(), self.ttl);
+        let report = tokio::task::spawn_blocking(move || read_entry(&dir, &key_owned, ttl))
+            .await
+            .ok()
+            .flatten()?;
+        let report = Arc::new(report);
+        self.lock_memory()
+            .insert(key, Arc::clone(&report), self.max_memory_bytes);
+        Some(report)
+    }
+
+    async fn insert(&self, key: &str, report: Arc<CachedReport>) {
+        self.lock_memory()
+            .insert(key, Arc::clone(&report), self.max_memory_bytes);
+        let Some(disk) = &self.disk else {
+            return;
+        };
+        let size = report.body.len() as u64;
+        let (dir, key_owned) = (disk.dir.clone(), key.to_string());
+        let written =
+            tokio::task::spawn_blocking(move || write_entry(&dir, &key_owned, &report)).await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                tracing::warn!("write render cache error: {}", e);
+                return;
+            }
+            Err(e) => {
+                tracing::warn!("write render cache panicked: {}", e);
+                return;
+            }
+        }
+        let stale = {
+            let mut index = disk.lock_index();
+            index.insert(key, SystemTime::now(), size);
+            index.evict(disk.max_bytes, self.ttl)
+        };
+        if stale.is_empty() {
+            return;
+        }
+        let dir = disk.dir.clone();
+        let removed = tokio::task::spawn_blocking(move || remove_entries(&dir, &stale)).await;
+        match removed {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!("prune render cache error: {}", e),
+            Err(e) => tracing::warn!("prune render cache panicked: {}", e),
+        }
+    }
+
+    fn lock_memory(&self) -> MutexGuard<'_, Memory> {
+        self.memory.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Disk {
+    /// Index the reports left in `dir` by an earlier process, removing the
+    /// partial writes it left behind.
+    fn open(dir: PathBuf, max_bytes: u64) -> Self {
+        let mut index = DiskIndex::default();
+        if let Err(e) = scan(&dir, &mut index) {
+            tracing::warn!("scan render cache {} error: {}", dir.display(), e);
+        }
+        Self {
+            dir,
+            max_bytes,
+            index: Mutex::new(index),
+        }
+    }
+
+    fn lock_index(&self) -> MutexGuard<'_, DiskIndex> {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl DiskIndex {
+    fn insert(&mut self, key: &str, modified: SystemTime, size: u64) {
+        self.remove(key);
+        self.entries.insert(key.to_string(), (modified, size));
+        self.order.insert((modified, key.to_string()));
+        self.bytes += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((modified, size)) = self.entries.remove(key) {
+            self.order.remove(&(modified, key.to_string()));
+            self.bytes -= size;
+        }
+    }
+
+    /// Drop expired entries, then the oldest ones until the rest fit
+    /// `max_bytes`, returning their keys.
+    fn evict(&mut self, max_bytes: u64, ttl: Duration) -> Vec<String> {
+        let mut evicted = vec![];
+        while let Some((modified, key)) = self.order.first().cloned() {
+            let expired = modified.elapsed().unwrap_or_default() >= ttl;
+            if !expired && self.bytes <= max_bytes {
+                break;
+            }
+            self.remove(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+impl Memory {
+    fn get(&mut self, key: &str, ttl: Duration) -> Option<Arc<CachedReport>> {
+        let entry = self.entries.get(key)?;
+        if entry.stored.elapsed() >= ttl {
+            self.remove(key);
+            return None;
+        }
+        let used = entry.used;
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        entry.used = tick;
+        self.order.remove(&used);
+        self.order.insert(tick, key.to_string());
+        Some(Arc::clone(&entry.report))
+    }
+
+    fn insert(&mut self, key: &str, report: Arc<CachedReport>, max_bytes: usize) {
+        let size = report.body.len();
+        if size > max_bytes {
+            return;
+        }
+        self.remove(key);
+        while self.bytes + size > max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.to_string());
+        self.entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                report,
+                stored: Instant::now(),
+                used: self.tick,
+            },
+        );
+        self.bytes += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.bytes -= entry.report.body.len();
+        }
+    }
+}
+
+fn meta_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{key}.json"))
+}
+
+fn read_entry(dir: &Path, key: &str, ttl: Duration) -> Option<CachedReport> {
+    let path = dir.join(key);
+    let modified = std::fs::metadata(&path).ok()?.modified().ok()?;
+    if modified.elapsed().unwrap_or_default() >= ttl {
+        return None;
+    }
+    let body = std::fs::read(&path).ok()?;
+    let meta = std::fs::read(meta_path(dir, key)).ok()?;
+    let mut report: CachedReport = serde_json::from_slice(&meta).ok()?;
+    report.body = Bytes::from(body);
+    Some(report)
+}
+
+/// Write the metadata, then move the body in place so readers never see a
+/// partial report.
+fn write_entry(dir: &Path, key: &str, report: &CachedReport) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(meta_path(dir, key), serde_json::to_vec(report)?)?;
+    let tmp = dir.join(format!("{key}.tmp"));
+    let written =
+        std::fs::write(&tmp, &report.body).and_then(|()| std::fs::rename(&tmp, dir.join(key)));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Add the reports in `dir` to `index`, removing temporary bodies and
+/// metadata without a body, which only an interrupted write leaves.
+fn scan(dir: &Path, index: &mut DiskIndex) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut metas = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.ends_with(".tmp") {
+            std::fs::remove_file(entry.path())?;
+        } else if let Some(key) = name.strip_suffix(".json") {
+            metas.push(key.to_string());
+        } else if !name.contains('.') {
+            let meta = entry.metadata()?;
+            let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            index.insert(&name, modified, meta.len());
+        }
+    }
+    for key in metas {
+        if !index.entries.contains_key(&key) {
+            std::fs::remove_file(meta_path(dir, &key))?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the bodies and metadata of `keys`.
+fn remove_entries(dir: &Path, keys: &[String]) -> std::io::Result<()> {
+    for key in keys {
+        let _ = std::fs::remove_file(meta_path(dir, key));
+        match std::fs::remove_file(dir.join(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn report(body: &'static str) -> CachedReport {
+        CachedReport {
+            content_type: "application/pdf".to_string(),
+            ext: Some(".pdf".to_string()),
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    fn memory_cache(max_memory_bytes: usize, ttl_secs: u64) -> RenderCache {
+        RenderCache::new(&CacheConfig {
+            max_memory_bytes,
+            ttl_secs,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used() {
+        let cache = memory_cache(8, 60);
+        for key in ["a", "b"] {
+            cache
+                .get_or_render(key, || async { Ok(report("1234")) })
+                .await
+                .unwrap();
+        }
+        // Using `a` makes `b` the one evicted by `c`.
+        let (_, status) = cache
+            .get_or_render("a", || async { unreachable!() })
+            .await
+            .unwrap();
+        assert_eq!(status, CacheStatus::Hit);
+        cache
+            .get_or_render("c", || async { Ok(report("5678")) })
+            .await
+            .unwrap();
+        assert!(cache.get("a").await.is_some());
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cache_expires_entries() {
+        let cache = memory_cache(1024, 0);
+        let (_, status) = cache
+            .get_or_render("a", || async { Ok(report("pdf")) })
+            .await
+            .unwrap();
+        assert_eq!(status, CacheStatus::Miss);
+        let (_, status) = cache
+            .get_or_render("a", || async { Ok(report("pdf")) })
+            .await
+            .unwrap();
+        assert_eq!(status, CacheStatus::Miss);
+    }
+
+    #[tokio::test]
+    async fn test_cache_coalesces_concurrent_renders() {
+        let cache = memory_cache(1024, 60);
+        let renders = AtomicUsize::new(0);
+        let render = || async {
+            renders.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(report("pdf"))
+        };
+        let (first, second) = tokio::join!(
+            cache.get_or_render("a", render),
+            cache.get_or_render("a", render)
+        );
+        assert_eq!(renders.load(Ordering::SeqCst), 1);
+        let mut statuses = [first.unwrap().1, second.unwrap().1];
+        statuses.sort_by_key(|status| status.as_str());
+        assert_eq!(statuses, [CacheStatus::Hit, CacheStatus::Miss]);
+    }
+
+    #[tokio::test]
+    async fn test_cache_failed_render_is_not_cached() {
+        let cache = memory_cache(1024, 60);
+        let result = cache
+            .get_or_render("a", || async {
+                Err(Error::InvalidInput {
+                    reason: "broken".to_string(),
+                })
+            })
+            .await;
+        assert!(result.is_err());
+        let (_, status) = cache
+            .get_or_render("a", || async { Ok(report("pdf")) })
+            .await
+            .unwrap();
+        assert_eq!(status, CacheStatus::Miss);
+    }
+
+    #[tokio::test]
+    async fn test_cache_disk_tier() {
+        let dir = std::env::temp_dir().join(format!("render-cache-{}", std::process::id()));
+        let config = CacheConfig {
+            dir: Some(dir.display().to_string()),
+            max_disk_bytes: 8,
+            ..Default::default()
+        };
+        let cache = RenderCache::new(&config);
+        for key in ["a", "b", "c"] {
+            cache
+                .get_or_render(key, || async { Ok(report("1234")) })
+                .await
+                .unwrap();
+        }
+
+        // A new process finds the newest reports on disk.
+        let cache = RenderCache::new(&config);
+        let (cached, status) = cache
+            .get_or_render("c", || async { unreachable!() })
+            .await
+            .unwrap();
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(cached.body, "1234");
+        assert_eq!(cached.ext.as_deref(), Some(".pdf"));
+        let entries = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| {
+                !entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .contains('.')
+            })
+            .count();
+        assert_eq!(entries, 2);
+        assert_eq!(cache.disk.as_ref().unwrap().lock_index().bytes, 8);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache_disk_removes_interrupted_writes() {
+        let dir = std::env::temp_dir().join(format!("render-cache-tmp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.tmp"), "12").unwrap();
+        std::fs::write(dir.join("a.json"), "{}").unwrap();
+        std::fs::write(dir.join("b"), "1234").unwrap();
+        std::fs::write(dir.join("b.json"), r#"{"content_type":"application/pdf"}"#).unwrap();
+
+        let cache = RenderCache::new(&CacheConfig {
+            dir: Some(dir.display().to_string()),
+            ..Default::default()
+        });
+        assert!(!dir.join("a.tmp").exists());
+        assert!(!dir.join("a.json").exists());
+        assert_eq!(cache.disk.as_ref().unwrap().lock_index().bytes, 4);
+        let (cached, status) = cache
+            .get_or_render("b", || async { unreachable!() })
+            .await
+            .unwrap();
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(cached.body, "1234");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_key_separates_parts() {
+        assert_ne!(
+            cache_key([b"ab".as_slice(), b"c"]),
+            cache_key([b"a".as_slice(), b"bc"])
+        );
+    }
+}
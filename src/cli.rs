@@ -0,0 +1,132 @@
+use std::path::PathBuf;
+
+use clap::{ArgGroup, Args, Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Export kube-eye reports with typst")]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// Defaults to `serve`.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server.
+    Serve,
+    /// Load and validate the configs, reporting every problem found.
+    CheckConfig,
+    /// Print the effective server config after all overrides.
+    PrintConfig,
+    /// Render a PDF with a theme exactly like the server, without serving.
+    Render(RenderArgs),
+}
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("entry").required(true).args(["input", "template"])))]
+pub struct RenderArgs {
+    #[arg(long, default_value = "default")]
+    pub theme: String,
+    /// Typst file compiled as the report, like `content` of `/api/report`.
+    #[arg(long)]
+    pub input: Option<PathBuf>,
+    /// Theme template compiled as the report instead of `--input`.
+    #[arg(long)]
+    pub template: Option<String>,
+    /// JSON object exposed as `sys.inputs` and `data.json`.
+    #[arg(long)]
+    pub data: Option<PathBuf>,
+    #[arg(long)]
+    pub out: PathBuf,
+}
+
+/// Where configs are read from and values overriding them.
+#[derive(Debug, Clone, Default, Args)]
+pub struct ConfigArgs {
+    /// Server config file, read instead of the default locations.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Client config file, read instead of the default locations; later
+    /// files override earlier ones.
+    #[arg(long = "client-config", global = true)]
+    pub client_config: Vec<PathBuf>,
+    /// Overrides `typst.assets_dir`.
+    #[arg(long, global = true)]
+    pub assets_dir: Option<String>,
+    /// Overrides `server.host`.
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+    /// Overrides `server.port`.
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_overrides_after_subcommand() {
+        let cli = Cli::try_parse_from([
+            "kube-eye-export-server",
+            "--config",
+            "Config.toml",
+            "check-config",
+            "--client-config",
+            "a.yaml",
+            "--client-config",
+            "b.yaml",
+            "--port",
+            "9090",
+        ])
+        .unwrap();
+        assert!(matches!(cli.command, Some(Command::CheckConfig)));
+        assert_eq!(cli.config.config, Some(PathBuf::from("Config.toml")));
+        assert_eq!(
+            cli.config.client_config,
+            vec![PathBuf::from("a.yaml"), PathBuf::from("b.yaml")]
+        );
+        assert_eq!(cli.config.port, Some(9090));
+        assert_eq!(cli.config.host, None);
+    }
+
+    #[test]
+    fn test_parse_render() {
+        let cli = Cli::try_parse_from([
+            "kube-eye-export-server",
+            "render",
+            "--input",
+            "report.typ",
+            "--data",
+            "data.json",
+            "--out",
+            "report.pdf",
+        ])
+        .unwrap();
+        let Some(Command::Render(args)) = cli.command else {
+            panic!("expected render");
+        };
+        assert_eq!(args.theme, "default");
+        assert_eq!(args.input, Some(PathBuf::from("report.typ")));
+        assert!(args.template.is_none());
+
+        let result = Cli::try_parse_from(["kube-eye-export-server", "render", "--out", "a.pdf"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_defaults_to_serve() {
+        let cli = Cli::try_parse_from(["kube-eye-export-server"]).unwrap();
+        assert!(cli.command.is_none());
+        assert!(cli.config.client_config.is_empty());
+    }
+}
@@ -8,21 +8,379 @@ pub struct Config {
     pub typst: TypstConfig,
 }
 
+/// Printed in place of a secret.
+const REDACTED: &str = "<redacted>";
+
+impl Config {
+    /// The config with its secrets replaced, safe to print.
+    pub fn redacted(mut self) -> Self {
+        let auth = &mut self.server.auth;
+        for token in &mut auth.tokens {
+            token.sha256 = REDACTED.to_string();
+        }
+        if let Some(secret) = auth.jwt.as_mut().and_then(|jwt| jwt.secret.as_mut()) {
+            *secret = REDACTED.to_string();
+        }
+        self
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub public_dir_dist: Vec<(String, String)>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub authorization: AuthorizationConfig,
+    /// Per-client limits of the report endpoints; unlimited when unset.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// How long in-flight reports may finish after SIGTERM/SIGINT.
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+    /// How long `/readyz` fails before the listeners close on shutdown, so
+    /// load balancers stop sending requests first; part of the drain timeout.
+    #[serde(default = "default_shutdown_delay_secs")]
+    pub shutdown_delay_secs: u64,
+    /// Serve HTTPS, and HTTP/2 via ALPN, instead of plain HTTP.
+    pub tls: Option<TlsConfig>,
+    /// Addresses served at once, each with its own routes; `host`:`port`
+    /// serving every route when empty.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Asynchronous report jobs under `/api/reports/jobs`; disabled when unset.
+    pub jobs: Option<JobsConfig>,
+    /// Keeps every rendered report for listing and re-download under
+    /// `/api/reports`; disabled when unset.
+    pub archive: Option<ArchiveConfig>,
+}
+
+/// Where finished report jobs are kept and for how long.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JobsConfig {
+    /// Directory of job results, emptied on startup.
+    pub dir: String,
+    /// How long a finished job and its file can be fetched.
+    #[serde(default = "default_job_retention_secs")]
+    pub retention_secs: u64,
+    /// Base of download links sent to `callback_url`, e.g.
+    /// `https://export.example.com`; links are relative when unset.
+    pub public_url: Option<String>,
+    /// Deliveries retried after the first one fails.
+    #[serde(default = "default_callback_retries")]
+    pub callback_retries: u32,
+    /// Pause before the first retry, doubled for every further one.
+    #[serde(default = "default_callback_backoff_ms")]
+    pub callback_backoff_ms: u64,
+    /// Hosts a `callback_url` may point to, `*.example.com` matching its
+    /// subdomains; callbacks are rejected when empty.
+    #[serde(default)]
+    pub callback_hosts: Vec<String>,
+    /// Unfinished jobs a user may have at once; more are rejected.
+    #[serde(default = "default_jobs_per_user")]
+    pub max_per_user: usize,
+    /// How long a job waits for room in a full render queue before it fails.
+    #[serde(default = "default_job_queue_timeout_secs")]
+    pub queue_timeout_secs: u64,
+}
+
+fn default_job_retention_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_jobs_per_user() -> usize {
+    10
+}
+
+fn default_job_queue_timeout_secs() -> u64 {
+    10 * 60
+}
+
+fn default_callback_retries() -> u32 {
+    5
+}
+
+fn default_callback_backoff_ms() -> u64 {
+    1000
+}
+
+/// Where archived reports are kept and when they are removed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ArchiveConfig {
+    /// Directory of archived reports and their metadata, kept across restarts.
+    #[serde(default)]
+    pub dir: String,
+    /// Keep reports in an S3-compatible bucket instead of `dir`.
+    pub s3: Option<S3Config>,
+    /// Remove reports older than this; kept forever when unset.
+    pub max_age_secs: Option<u64>,
+    /// Remove the oldest reports while all of them together are larger.
+    pub max_total_bytes: Option<u64>,
+}
+
+/// A prefix of an S3-compatible bucket.
+///
+/// The endpoint, bucket, region and credentials come from `S3_ENDPOINT`,
+/// `S3_BUCKET`, `AWS_REGION`, `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`
+/// and `AWS_SESSION_TOKEN`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct S3Config {
+    /// Overrides `S3_BUCKET`.
+    pub bucket: Option<String>,
+    /// Prepended to every object key, e.g. `reports/`.
+    pub prefix: String,
+}
+
+/// One address the server listens on.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ListenerConfig {
+    /// `host:port`, or `unix:<path>` for a Unix domain socket.
+    pub bind: String,
+    #[serde(default = "RouteGroup::all")]
+    pub routes: Vec<RouteGroup>,
+    /// Serve `server.tls` here when configured; never used on Unix sockets.
+    #[serde(default = "default_listener_tls")]
+    pub tls: bool,
+}
+
+fn default_listener_tls() -> bool {
+    true
+}
+
+/// Routes enabled together on a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteGroup {
+    /// `public_dir_dist` directories.
+    Static,
+    /// `/`, `/version`, `/health`, `/livez` and `/readyz`.
+    Health,
+    /// `/metrics`.
+    Metrics,
+    /// `/api/client_config`.
+    ClientConfig,
+    /// `/api/report`, `/api/report/batch`, `/api/report/{theme}/{template}`,
+    /// `/api/reports` and `/api/reports/jobs`.
+    Report,
+}
+
+impl RouteGroup {
+    pub fn all() -> Vec<Self> {
+        vec![
+            Self::Static,
+            Self::Health,
+            Self::Metrics,
+            Self::ClientConfig,
+            Self::Report,
+        ]
+    }
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+fn default_shutdown_delay_secs() -> u64 {
+    5
+}
+
+/// PEM files of the server certificate, reloaded when they change.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    pub cert_file: String,
+    pub key_file: String,
+    /// Require client certificates signed by this CA (mTLS).
+    pub client_ca_file: Option<String>,
+}
+
+/// Token bucket and concurrency limits, keyed by user id or client IP.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Requests a client may send at once before it is throttled.
+    pub burst: u32,
+    /// Requests per minute added back to a client's bucket.
+    pub per_minute: u32,
+    /// Renders a single client may have in flight.
+    pub max_concurrent_renders: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            burst: 10,
+            per_minute: 60,
+            max_concurrent_renders: 2,
+        }
+    }
+}
+
+/// Who may render which theme and read the client config.
+///
+/// Without rules every authenticated caller is allowed everything.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthorizationConfig {
+    pub rules: Vec<AuthorizationRule>,
+}
+
+/// Grants `themes` and optionally the client config and the reports of all
+/// users to the listed users and groups; `*` matches anything.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthorizationRule {
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+    pub themes: Vec<String>,
+    pub client_config: bool,
+    /// List and download archived reports of every user, not only their own.
+    pub archive: bool,
+}
+
+/// Verifiers for the bearer token of `/api` requests, tried in order.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub tokens: Vec<StaticToken>,
+    pub jwt: Option<JwtConfig>,
+    pub token_review: Option<TokenReviewConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StaticToken {
+    /// Hex encoded sha256 of the token.
+    pub sha256: String,
+    pub user_id: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm,
+    /// HS256 shared secret.
+    pub secret: Option<String>,
+    /// RS256 public key in PEM format.
+    pub public_key_file: Option<String>,
+    /// JWKS file; the key is picked by the token's `kid`.
+    pub jwks_file: Option<String>,
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audience: Vec<String>,
+    /// Claim holding the user id.
+    #[serde(default = "default_user_claim")]
+    pub user_claim: String,
+    #[serde(default)]
+    pub leeway_secs: u64,
+}
+
+fn default_user_claim() -> String {
+    "sub".to_string()
+}
+
+/// Kubernetes `TokenReview` authentication.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TokenReviewConfig {
+    /// e.g. `https://kubernetes.default.svc`.
+    pub api_server: String,
+    /// CA bundle of the API server in PEM format.
+    pub ca_file: Option<String>,
+    /// Token this server authenticates to the API server with, e.g. its
+    /// service account token.
+    pub token_file: Option<String>,
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// How long an authenticated token is trusted without a new review.
+    #[serde(default = "default_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    30
+}
+
+fn default_timeout_secs() -> u64 {
+    5
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TypstConfig {
     pub assets_dir: String,
+    /// Download fonts and templates from this bucket prefix into `assets_dir`
+    /// on startup.
+    pub assets_s3: Option<S3Config>,
     pub themes: HashMap<String, Theme>,
     pub icons: HashMap<String, String>,
+    #[serde(default)]
+    pub render: RenderConfig,
+    /// Start with an invalid config, only logging its problems.
+    #[serde(default)]
+    pub lenient: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RenderConfig {
+    /// Number of dedicated render threads.
+    pub workers: usize,
+    /// Number of renders allowed to wait for a free worker.
+    pub queue_size: usize,
+    /// Wall-clock limit of a single render.
+    pub timeout_secs: u64,
+    /// `Retry-After` sent back when the queue is full.
+    pub retry_after_secs: u64,
+    /// Serve identical report requests from a cache; disabled when unset.
+    pub cache: Option<CacheConfig>,
+}
+
+/// Limits of the render cache.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Bytes of rendered reports kept in memory.
+    pub max_memory_bytes: usize,
+    /// How long a rendered report is served from the cache.
+    pub ttl_secs: u64,
+    /// Directory of the on-disk tier, only memory is used when unset.
+    pub dir: Option<String>,
+    /// Bytes of rendered reports kept in `dir`.
+    pub max_disk_bytes: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_memory_bytes: 256 * 1024 * 1024,
+            ttl_secs: 3600,
+            dir: None,
+            max_disk_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(2, |n| n.get()),
+            queue_size: 32,
+            timeout_secs: 60,
+            retry_after_secs: 5,
+            cache: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Theme {
     #[serde(default)]
     pub icons: Vec<String>,
@@ -43,11 +401,23 @@ mod tests {
                 public_dir_dist: vec![
                     ("./dist".to_string(), "/static".to_string()),
                 ],
+                auth: AuthConfig::default(),
+                authorization: AuthorizationConfig::default(),
+                rate_limit: None,
+                drain_timeout_secs: 30,
+                shutdown_delay_secs: 5,
+                tls: None,
+                listeners: vec![],
+                jobs: None,
+                archive: None,
             },
             typst: TypstConfig {
                 assets_dir: "./assets".to_string(),
+                assets_s3: None,
                 themes: HashMap::new(),
                 icons: HashMap::new(),
+                render: RenderConfig::default(),
+                lenient: false,
             },
         };
 
@@ -75,6 +445,67 @@ mod tests {
         assert_eq!(config.server.host, "0.0.0.0");
         assert_eq!(config.server.port, 3000);
         assert_eq!(config.typst.assets_dir, "./typst_assets");
+        assert_eq!(config.typst.render.queue_size, 32);
+    }
+
+    #[test]
+    fn test_auth_config_deserialization() {
+        let json = r#"{
+            "tokens": [{"sha256": "abc", "user_id": "ci"}],
+            "jwt": {"algorithm": "RS256", "public_key_file": "jwt.pem", "issuer": "kube-eye"}
+        }"#;
+        let auth: AuthConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(auth.tokens[0].user_id, "ci");
+        let jwt = auth.jwt.unwrap();
+        assert!(matches!(jwt.algorithm, JwtAlgorithm::RS256));
+        assert_eq!(jwt.user_claim, "sub");
+        assert!(jwt.audience.is_empty());
+    }
+
+    #[test]
+    fn test_config_redacted() {
+        let json = r#"{
+            "server": {
+                "host": "0.0.0.0",
+                "port": 3000,
+                "public_dir_dist": [],
+                "auth": {
+                    "tokens": [{"sha256": "abc", "user_id": "ci"}],
+                    "jwt": {"algorithm": "HS256", "secret": "jwt-secret"}
+                }
+            },
+            "typst": {"assets_dir": "./typst_assets", "themes": {}, "icons": {}}
+        }"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        let printed = toml::to_string_pretty(&config.redacted()).unwrap();
+        assert!(!printed.contains("abc"));
+        assert!(!printed.contains("jwt-secret"));
+        assert!(printed.contains("user_id = \"ci\""));
+    }
+
+    #[test]
+    fn test_listeners_deserialization() {
+        let json = r#"[
+            {"bind": "0.0.0.0:8080", "routes": ["static", "client_config"]},
+            {"bind": "unix:/run/export.sock", "tls": false}
+        ]"#;
+        let listeners: Vec<ListenerConfig> = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            listeners[0].routes,
+            vec![RouteGroup::Static, RouteGroup::ClientConfig]
+        );
+        assert!(listeners[0].tls);
+        assert_eq!(listeners[1].routes, RouteGroup::all());
+        assert!(!listeners[1].tls);
+    }
+
+    #[test]
+    fn test_render_config_partial() {
+        let json = r#"{"workers": 3, "timeout_secs": 10}"#;
+        let render: RenderConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(render.workers, 3);
+        assert_eq!(render.timeout_secs, 10);
+        assert_eq!(render.queue_size, 32);
     }
 
     #[test]
@@ -111,6 +542,15 @@ mod tests {
                 ("./public".to_string(), "/public".to_string()),
                 ("./assets".to_string(), "/assets".to_string()),
             ],
+            auth: AuthConfig::default(),
+            authorization: AuthorizationConfig::default(),
+            rate_limit: None,
+            drain_timeout_secs: 30,
+            shutdown_delay_secs: 5,
+            tls: None,
+            listeners: vec![],
+            jobs: None,
+            archive: None,
         };
 
         assert_eq!(config.public_dir_dist.len(), 3);
@@ -131,8 +571,11 @@ mod tests {
 
         let config = TypstConfig {
             assets_dir: "./assets".to_string(),
+            assets_s3: None,
             themes,
             icons,
+            render: RenderConfig::default(),
+            lenient: false,
         };
 
         assert_eq!(config.themes.len(), 1);
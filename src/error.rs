@@ -1,6 +1,6 @@
 use axum::{
     extract::rejection::JsonRejection,
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
     response::{IntoResponse, Response},
 };
 use color_eyre::eyre::Report;
@@ -8,6 +8,8 @@ use serde::Serialize;
 use snafu::Snafu;
 use tracing::error;
 
+use crate::typst_lib::Diagnostic;
+
 #[derive(Debug, Snafu)]
 #[snafu(visibility(pub))]
 pub enum Error {
@@ -45,8 +47,8 @@ pub enum Error {
         source,
     ))]
     FigmentParse {
-        #[snafu(source)]
-        source: figment::Error,
+        #[snafu(source(from(figment::Error, Box::new)))]
+        source: Box<figment::Error>,
         #[snafu(implicit)]
         loc: snafu::Location,
     },
@@ -83,9 +85,73 @@ pub enum Error {
         #[snafu(implicit)]
         loc: snafu::Location,
     },
+    #[snafu(display("{}: Failed to write zip: {:#?}\n {:#?}\n", loc, source, backtrace))]
+    Zip {
+        #[snafu(source)]
+        source: zip::result::ZipError,
+        #[snafu(backtrace)]
+        backtrace: snafu::Backtrace,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+    #[snafu(display("{}: Failed to load jwt key: {}", loc, source))]
+    JwtKey {
+        source: jsonwebtoken::errors::Error,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+    #[snafu(display("{}: Token review failed: {}", loc, source))]
+    TokenReview {
+        source: reqwest::Error,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+    #[snafu(display("{}: Job callback failed: {}", loc, source))]
+    Webhook {
+        source: reqwest::Error,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+    #[snafu(display("{}: S3 request failed: {}", loc, source))]
+    S3 {
+        source: reqwest::Error,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+    #[snafu(display("S3 {method} {key} answered {status}: {message}"))]
+    S3Status {
+        method: String,
+        key: String,
+        status: u16,
+        message: String,
+    },
+    #[snafu(display("{}: Invalid S3 listing: {}", loc, source))]
+    S3Listing {
+        source: quick_xml::DeError,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
     #[snafu(display("Failed to generate pdf:{}", message))]
     TypstPdf { message: String },
 
+    #[snafu(display("Failed to compile typst document"))]
+    TypstCompile { diagnostics: Vec<Diagnostic> },
+
+    #[snafu(display("Render queue is full, retry after {retry_after}s"))]
+    RenderQueueFull { retry_after: u64 },
+
+    #[snafu(display("Too many requests, retry after {retry_after}s"))]
+    RateLimited { retry_after: u64 },
+
+    #[snafu(display("Too many concurrent renders, at most {limit} allowed"))]
+    TooManyRenders { limit: usize, retry_after: u64 },
+
+    #[snafu(display("Too many unfinished jobs, at most {limit} allowed"))]
+    TooManyJobs { limit: usize },
+
+    #[snafu(display("Render timed out after {timeout_secs}s"))]
+    RenderTimeout { timeout_secs: u64 },
+
     #[snafu(display("Invalid input: {reason}"))]
     InvalidInput { reason: String },
 
@@ -98,6 +164,18 @@ pub enum Error {
     #[snafu(display("Invalid Token"))]
     InvalidToken,
 
+    #[snafu(display("Forbidden: {reason}"))]
+    Forbidden { reason: String },
+
+    #[snafu(display("Job {id} not found"))]
+    JobNotFound { id: String },
+
+    #[snafu(display("Job {id} is {status}, not done"))]
+    JobNotDone { id: String, status: String },
+
+    #[snafu(display("Report {id} not found"))]
+    ReportNotFound { id: String },
+
     #[snafu(display("Invalid Json Body. {}", source))]
     InvalidJsonBody {
         source: axum::extract::rejection::JsonRejection,
@@ -105,6 +183,52 @@ pub enum Error {
         loc: snafu::Location,
     },
 
+    #[snafu(display("Invalid Query. {}", source))]
+    InvalidQuery {
+        source: axum::extract::rejection::QueryRejection,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+
+    #[snafu(display("{}: Failed to serialize config: {}", loc, source))]
+    TomlSerialize {
+        source: toml::ser::Error,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+
+    #[snafu(display("{}: Failed to serialize json: {}", loc, source))]
+    JsonSerialize {
+        source: serde_json::Error,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+
+    #[snafu(display("Invalid typst config:\n  - {}", problems.join("\n  - ")))]
+    InvalidConfig { problems: Vec<String> },
+
+    #[snafu(display("{}: Invalid tls config: {}", loc, source))]
+    Tls {
+        source: rustls::Error,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+
+    #[snafu(display("{}: Failed to read {}: {}", loc, path, source))]
+    TlsPem {
+        source: rustls::pki_types::pem::Error,
+        path: String,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+
+    #[snafu(display("{}: Invalid tls client CA: {}", loc, source))]
+    TlsClientCa {
+        source: rustls::server::VerifierBuilderError,
+        #[snafu(implicit)]
+        loc: snafu::Location,
+    },
+
     #[snafu(display("Internal Error: {}", source))]
     Internal { source: Report },
 
@@ -121,6 +245,8 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 struct ErrorResponse {
     code: u32,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diagnostics: Option<Vec<Diagnostic>>,
 }
 
 impl From<JsonRejection> for Error {
@@ -138,15 +264,75 @@ impl From<color_eyre::Report> for Error {
     }
 }
 
+impl Error {
+    /// Short label of the error, used as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::MissingAuth => "missing_auth",
+            Error::InvalidToken => "invalid_token",
+            Error::TokenReview { .. } => "token_review",
+            Error::Forbidden { .. } => "forbidden",
+            Error::TypstCompile { .. } => "compile",
+            Error::RenderQueueFull { .. } => "queue_full",
+            Error::RenderTimeout { .. } => "timeout",
+            Error::InvalidInput { .. } => "invalid_input",
+            Error::RateLimited { .. } | Error::TooManyRenders { .. } => "rate_limited",
+            _ => "internal",
+        }
+    }
+
+    /// Seconds to wait before trying again when the server or the quota of the
+    /// client is busy, sent as `Retry-After`.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Error::RenderQueueFull { retry_after }
+            | Error::RateLimited { retry_after }
+            | Error::TooManyRenders { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         error!("❌ API Error: {:#?}", self);
+        let retry_after = self.retry_after();
+        let diagnostics = match &self {
+            Error::TypstCompile { diagnostics } => Some(diagnostics.clone()),
+            _ => None,
+        };
         let (status, code, message) = match self {
             Error::MissingAuth => (StatusCode::UNAUTHORIZED, 1001, self.to_string()),
+            Error::BadRequest { .. } => (StatusCode::BAD_REQUEST, 1002, self.to_string()),
             Error::InvalidToken => (StatusCode::UNAUTHORIZED, 1003, self.to_string()),
+            Error::Forbidden { .. } => (StatusCode::FORBIDDEN, 1004, self.to_string()),
+            Error::JobNotFound { .. } => (StatusCode::NOT_FOUND, 1010, self.to_string()),
+            Error::JobNotDone { .. } => (StatusCode::CONFLICT, 1011, self.to_string()),
+            Error::ReportNotFound { .. } => (StatusCode::NOT_FOUND, 1012, self.to_string()),
+            Error::InvalidInput { .. } => (StatusCode::BAD_REQUEST, 1013, self.to_string()),
+            Error::TokenReview { .. } => (
+                StatusCode::BAD_GATEWAY,
+                5002,
+                "Token review is unavailable".to_string(),
+            ),
             Error::InvalidJsonBody { .. } => {
                 (StatusCode::UNPROCESSABLE_ENTITY, 1005, self.to_string())
             }
+            Error::InvalidQuery { .. } => {
+                (StatusCode::UNPROCESSABLE_ENTITY, 1007, self.to_string())
+            }
+            Error::TypstCompile { .. } => {
+                (StatusCode::UNPROCESSABLE_ENTITY, 1006, self.to_string())
+            }
+            Error::RenderQueueFull { .. } => {
+                (StatusCode::SERVICE_UNAVAILABLE, 5003, self.to_string())
+            }
+            Error::RenderTimeout { .. } => (StatusCode::GATEWAY_TIMEOUT, 5004, self.to_string()),
+            Error::RateLimited { .. } => (StatusCode::TOO_MANY_REQUESTS, 1008, self.to_string()),
+            Error::TooManyRenders { .. } => {
+                (StatusCode::TOO_MANY_REQUESTS, 1009, self.to_string())
+            }
+            Error::TooManyJobs { .. } => (StatusCode::TOO_MANY_REQUESTS, 1014, self.to_string()),
             // Error::TypstPdf { message } => {
             //     (StatusCode::INTERNAL_SERVER_ERROR, 5000, self.to_string())
             // }
@@ -162,8 +348,18 @@ impl IntoResponse for Error {
             // ),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, 5000, self.to_string()),
         };
-        let body = axum::Json(ErrorResponse { code, message });
-        (status, body).into_response()
+        let body = axum::Json(ErrorResponse {
+            code,
+            message,
+            diagnostics,
+        });
+        let mut response = (status, body).into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 }
 
@@ -222,13 +418,32 @@ mod tests {
         assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
     }
 
+    #[test]
+    fn test_error_into_response_forbidden() {
+        let error = Error::Forbidden {
+            reason: "theme internal is not allowed".to_string(),
+        };
+        assert_eq!(error.to_string(), "Forbidden: theme internal is not allowed");
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
     #[test]
     fn test_error_into_response_bad_request() {
         let error = Error::BadRequest {
             message: "test".to_string(),
         };
         let response = error.into_response();
-        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_error_into_response_invalid_input() {
+        let error = Error::InvalidInput {
+            reason: "Theme missing not found".to_string(),
+        };
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
 
     #[test]
@@ -240,15 +455,50 @@ mod tests {
         assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
 
+    #[test]
+    fn test_error_into_response_render_queue_full() {
+        let error = Error::RenderQueueFull { retry_after: 3 };
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "3");
+    }
+
+    #[test]
+    fn test_error_into_response_rate_limited() {
+        let error = Error::RateLimited { retry_after: 2 };
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "2");
+    }
+
+    #[test]
+    fn test_error_into_response_render_timeout() {
+        let error = Error::RenderTimeout { timeout_secs: 30 };
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(response.headers().get(RETRY_AFTER).is_none());
+    }
+
     #[test]
     fn test_error_response_serialization() {
         let error_response = ErrorResponse {
             code: 1001,
             message: "Test error".to_string(),
+            diagnostics: None,
         };
         let json = serde_json::to_string(&error_response).unwrap();
         assert!(json.contains("1001"));
         assert!(json.contains("Test error"));
+        assert!(!json.contains("diagnostics"));
+    }
+
+    #[test]
+    fn test_error_into_response_typst_compile() {
+        let error = Error::TypstCompile {
+            diagnostics: vec![],
+        };
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
     }
 
     #[test]
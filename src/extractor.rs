@@ -2,12 +2,13 @@ use std::ops::Deref;
 
 use axum::{
     Json,
-    extract::{FromRequest, Request},
+    extract::{FromRequest, FromRequestParts, Query, Request},
+    http::request::Parts,
 };
 use serde::de::DeserializeOwned;
 use snafu::ResultExt;
 
-use crate::error::{Error as ApiError, InvalidJsonBodySnafu, Result};
+use crate::error::{Error as ApiError, InvalidJsonBodySnafu, InvalidQuerySnafu, Result};
 
 pub struct ValidatedJson<T>(pub T);
 
@@ -33,6 +34,30 @@ where
     }
 }
 
+pub struct ValidatedQuery<T>(pub T);
+
+impl<T> Deref for ValidatedQuery<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T, S> FromRequestParts<S> for ValidatedQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(value) = Query::<T>::from_request_parts(parts, state)
+            .await
+            .context(InvalidQuerySnafu)?;
+        Ok(ValidatedQuery(value))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,6 +159,38 @@ mod tests {
         assert!(response.status().is_client_error() || response.status().is_server_error());
     }
 
+    async fn test_query_handler(ValidatedQuery(payload): ValidatedQuery<TestPayload>) -> String {
+        format!("{}-{}", payload.name, payload.value)
+    }
+
+    #[tokio::test]
+    async fn test_validated_query_with_valid_params() {
+        let app = Router::new().route("/test", post(test_query_handler));
+
+        let request = Request::builder()
+            .uri("/test?name=test&value=42")
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_validated_query_with_invalid_params() {
+        let app = Router::new().route("/test", post(test_query_handler));
+
+        let request = Request::builder()
+            .uri("/test?name=test&value=abc")
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 422);
+    }
+
     #[tokio::test]
     async fn test_validated_json_with_extra_fields() {
         let app = Router::new().route("/test", post(test_handler));
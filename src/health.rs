@@ -0,0 +1,178 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, PoisonError, Weak},
+    time::{Duration, Instant},
+};
+
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    server::ServerState,
+    typst_lib::{Renderer, generate_pdf},
+    validate::{DEFAULT_THEME, theme_problems},
+};
+
+const SMOKE_DOCUMENT: &str = "= Ready";
+
+/// How long a smoke compile result answers readiness probes.
+const SMOKE_TTL: Duration = Duration::from_secs(30);
+
+/// Last smoke compile result, kept until it expires or the renderer is
+/// swapped, so probes do not compete with renders for the pool.
+#[derive(Default)]
+pub struct SmokeCheck {
+    last: Mutex<Option<SmokeResult>>,
+}
+
+struct SmokeResult {
+    checked: Instant,
+    renderer: Weak<Renderer>,
+    problems: Vec<String>,
+}
+
+impl SmokeCheck {
+    fn cached(&self, renderer: &Arc<Renderer>) -> Option<Vec<String>> {
+        let last = self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        last.as_ref()
+            .filter(|last| {
+                last.checked.elapsed() < SMOKE_TTL
+                    && Weak::ptr_eq(&last.renderer, &Arc::downgrade(renderer))
+            })
+            .map(|last| last.problems.clone())
+    }
+
+    fn store(&self, renderer: &Arc<Renderer>, problems: Vec<String>) {
+        *self.last.lock().unwrap_or_else(PoisonError::into_inner) = Some(SmokeResult {
+            checked: Instant::now(),
+            renderer: Arc::downgrade(renderer),
+            problems,
+        });
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, problems: Vec<String>) -> Self {
+        Self {
+            name: name.into(),
+            ok: problems.is_empty(),
+            problems,
+        }
+    }
+}
+
+/// Compile a tiny document on the render pool, at most once per `SMOKE_TTL`.
+async fn smoke_compile(state: &ServerState) -> Vec<String> {
+    let typst = state.typst.load_full();
+    let config = &typst.config;
+    let Some(theme) = config
+        .themes
+        .keys()
+        .find(|name| *name == DEFAULT_THEME)
+        .or_else(|| config.themes.keys().next())
+        .cloned()
+    else {
+        return vec!["no theme configured".to_string()];
+    };
+    let renderer = &typst.renderer;
+    if let Some(problems) = state.smoke_check.cached(renderer) {
+        return problems;
+    }
+    let result = state
+        .render_pool
+        .run({
+            let renderer = Arc::clone(renderer);
+            move || generate_pdf(SMOKE_DOCUMENT.to_string(), &renderer, &theme)
+        })
+        .await;
+    let problems = match result {
+        Ok(_) => vec![],
+        // Workers busy with reports are not broken; check on the next probe.
+        Err(Error::RenderQueueFull { .. }) => return vec![],
+        Err(e) => vec![e.to_string()],
+    };
+    state.smoke_check.store(renderer, problems.clone());
+    problems
+}
+
+pub async fn check_readiness(state: &ServerState) -> Readiness {
+    if state.draining.is_cancelled() {
+        return Readiness {
+            ready: false,
+            checks: vec![Check::new(
+                "shutdown",
+                vec!["server is shutting down".to_string()],
+            )],
+        };
+    }
+    let config = Arc::clone(&state.typst.load().config);
+    let mut checks = vec![];
+
+    let assets_dir = Path::new(&config.assets_dir);
+    checks.push(Check::new(
+        "assets_dir",
+        if assets_dir.is_dir() {
+            vec![]
+        } else {
+            vec![format!("{} is not a directory", config.assets_dir)]
+        },
+    ));
+
+    let mut themes: Vec<_> = config.themes.iter().collect();
+    themes.sort_by_key(|(name, _)| *name);
+    for (name, theme) in themes {
+        checks.push(Check::new(
+            format!("theme:{name}"),
+            theme_problems(&config, name, theme, false),
+        ));
+    }
+
+    checks.push(Check::new("smoke_compile", smoke_compile(state).await));
+
+    checks.push(Check::new(
+        "client_config",
+        match state.client_config_error.load_full() {
+            Some(e) => vec![format!("client config failed to load: {e}")],
+            None => vec![],
+        },
+    ));
+
+    Readiness {
+        ready: checks.iter().all(|check| check.ok),
+        checks,
+    }
+}
+
+/// The process is up and serving requests.
+pub async fn livez() -> impl IntoResponse {
+    "ok"
+}
+
+/// The server can render reports; 503 with the failing checks otherwise.
+pub async fn readyz(State(state): State<ServerState>) -> impl IntoResponse {
+    let readiness = check_readiness(&state).await;
+    if !readiness.ready {
+        tracing::warn!("not ready: {:?}", readiness.checks);
+    }
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
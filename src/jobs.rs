@@ -0,0 +1,622 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    process,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    Extension, Json,
+    body::Bytes,
+    extract::{Path, State},
+    http::{StatusCode, header::LOCATION},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ResultExt, ensure};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    archive,
+    auth::AuthInfo,
+    cache::{CachedReport, cache_key},
+    config::JobsConfig,
+    error::{
+        Error, FileIoSnafu, InvalidInputSnafu, JobNotDoneSnafu, JobNotFoundSnafu, Result,
+        TooManyJobsSnafu,
+    },
+    extractor::ValidatedJson,
+    rate_limit::RenderPermit,
+    render_pool::retry_busy,
+    server::{RenderRequest, ReportRequest, ServerState, render_packed, report_response},
+    typst_lib::Diagnostic,
+    webhook::{self, Callback, JobNotification, Notifier},
+};
+
+/// Longest pause between two sweeps of expired jobs.
+const RETENTION_SWEEP: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for a render worker.
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        })
+    }
+}
+
+/// Status of a job as returned by the API.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub status: JobStatus,
+    pub name: String,
+    pub theme: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Size of the result in bytes once done.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    /// Hex encoded sha256 of the result once done.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+struct Job {
+    info: JobInfo,
+    /// User id of the creator, the only one who may see the job.
+    owner: String,
+    /// Content type and extension of the result, whose body is on disk.
+    result: Option<CachedReport>,
+    cancel: CancellationToken,
+    /// Render slot of the owner, held until the job finished.
+    permit: Option<Arc<RenderPermit>>,
+}
+
+/// Report jobs of this process, their results stored under a local directory.
+///
+/// Jobs are kept in memory, so they do not survive a restart.
+pub struct JobStore {
+    dir: PathBuf,
+    retention: Duration,
+    max_per_user: usize,
+    queue_timeout: Duration,
+    jobs: Mutex<HashMap<String, Job>>,
+    notifier: Notifier,
+    public_url: Option<String>,
+    callback_hosts: Vec<String>,
+}
+
+/// Unique 32 hex digit id of a job or archived report.
+pub(crate) fn new_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut id = cache_key([
+        nanos.to_le_bytes().as_slice(),
+        NEXT.fetch_add(1, Ordering::SeqCst).to_le_bytes().as_slice(),
+        process::id().to_le_bytes().as_slice(),
+    ]);
+    id.truncate(32);
+    id
+}
+
+pub(crate) fn is_id(name: &str) -> bool {
+    name.len() == 32 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl JobStore {
+    /// Create `dir`, removing results left behind by a previous process.
+    pub fn new(config: &JobsConfig) -> Result<Self> {
+        let dir = PathBuf::from(&config.dir);
+        std::fs::create_dir_all(&dir).context(FileIoSnafu)?;
+        for entry in std::fs::read_dir(&dir).context(FileIoSnafu)? {
+            let entry = entry.context(FileIoSnafu)?;
+            if is_id(&entry.file_name().to_string_lossy()) {
+                std::fs::remove_file(entry.path()).context(FileIoSnafu)?;
+            }
+        }
+        Ok(Self {
+            dir,
+            retention: Duration::from_secs(config.retention_secs),
+            max_per_user: config.max_per_user.max(1),
+            queue_timeout: Duration::from_secs(config.queue_timeout_secs),
+            jobs: Mutex::default(),
+            notifier: Notifier::new(config)?,
+            public_url: config.public_url.clone(),
+            callback_hosts: config.callback_hosts.clone(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Job>> {
+        self.jobs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Register a queued job of `owner`, cancelled through the returned token,
+    /// unless the owner already has too many unfinished jobs.
+    pub fn create(
+        &self,
+        owner: &str,
+        name: &str,
+        theme: &str,
+        permit: Option<Arc<RenderPermit>>,
+    ) -> Result<(JobInfo, CancellationToken)> {
+        let mut jobs = self.lock();
+        let unfinished = jobs
+            .values()
+            .filter(|job| job.owner == owner && job.info.finished_at.is_none())
+            .count();
+        ensure!(
+            unfinished < self.max_per_user,
+            TooManyJobsSnafu {
+                limit: self.max_per_user
+            }
+        );
+        let info = JobInfo {
+            id: new_id(),
+            status: JobStatus::Queued,
+            name: name.to_string(),
+            theme: theme.to_string(),
+            created_at: Utc::now(),
+            finished_at: None,
+            size: None,
+            sha256: None,
+            error: None,
+            diagnostics: vec![],
+        };
+        let cancel = CancellationToken::new();
+        jobs.insert(
+            info.id.clone(),
+            Job {
+                info: info.clone(),
+                owner: owner.to_string(),
+                result: None,
+                cancel: cancel.clone(),
+                permit,
+            },
+        );
+        Ok((info, cancel))
+    }
+
+    pub fn get(&self, owner: &str, id: &str) -> Result<JobInfo> {
+        match self.lock().get(id) {
+            Some(job) if job.owner == owner => Ok(job.info.clone()),
+            _ => JobNotFoundSnafu { id }.fail(),
+        }
+    }
+
+    pub fn set_running(&self, id: &str) {
+        if let Some(job) = self.lock().get_mut(id) {
+            job.info.status = JobStatus::Running;
+        }
+    }
+
+    /// Store the result of job `id`, returning its final status unless it was
+    /// cancelled meanwhile.
+    pub async fn finish(&self, id: &str, result: Result<CachedReport>) -> Option<JobInfo> {
+        let path = self.dir.join(id);
+        let result = match result {
+            Ok(report) => tokio::fs::write(&path, &report.body)
+                .await
+                .context(FileIoSnafu)
+                .map(|()| report),
+            Err(e) => Err(e),
+        };
+        let mut jobs = self.lock();
+        let Some(job) = jobs.get_mut(id) else {
+            let _ = std::fs::remove_file(&path);
+            return None;
+        };
+        job.info.finished_at = Some(Utc::now());
+        job.permit = None;
+        match result {
+            Ok(report) => {
+                job.info.status = JobStatus::Done;
+                job.info.size = Some(report.body.len());
+                job.info.sha256 = Some(format!("{:x}", Sha256::digest(&report.body)));
+                job.result = Some(CachedReport {
+                    body: Bytes::new(),
+                    ..report
+                });
+            }
+            Err(e) => {
+                job.info.status = JobStatus::Failed;
+                job.info.error = Some(e.to_string());
+                if let Error::TypstCompile { diagnostics } = e {
+                    job.info.diagnostics = diagnostics;
+                }
+            }
+        }
+        Some(job.info.clone())
+    }
+
+    /// The result of a done job of `owner`.
+    pub async fn file(&self, owner: &str, id: &str) -> Result<(JobInfo, CachedReport)> {
+        let (info, result) = match self.lock().get(id) {
+            Some(job) if job.owner == owner => (job.info.clone(), job.result.clone()),
+            _ => return JobNotFoundSnafu { id }.fail(),
+        };
+        let Some(report) = result else {
+            return JobNotDoneSnafu {
+                id,
+                status: info.status.to_string(),
+            }
+            .fail();
+        };
+        let body = tokio::fs::read(self.dir.join(id))
+            .await
+            .context(FileIoSnafu)?;
+        Ok((
+            info,
+            CachedReport {
+                body: body.into(),
+                ..report
+            },
+        ))
+    }
+
+    /// Stop the job of `owner` if it is unfinished, and forget it.
+    pub async fn cancel(&self, owner: &str, id: &str) -> Result<()> {
+        let job = {
+            let mut jobs = self.lock();
+            match jobs.get(id) {
+                Some(job) if job.owner == owner => jobs.remove(id),
+                _ => None,
+            }
+        };
+        let Some(job) = job else {
+            return JobNotFoundSnafu { id }.fail();
+        };
+        job.cancel.cancel();
+        if job.result.is_some() {
+            tokio::fs::remove_file(self.dir.join(id))
+                .await
+                .context(FileIoSnafu)?;
+        }
+        Ok(())
+    }
+
+    /// Forget jobs finished longer than the retention ago, with their files.
+    pub async fn remove_expired(&self) {
+        let now = Utc::now();
+        let expired: Vec<String> = {
+            let mut jobs = self.lock();
+            let expired: Vec<String> = jobs
+                .values()
+                .filter(|job| {
+                    job.info.finished_at.is_some_and(|finished| {
+                        (now - finished).to_std().unwrap_or_default() >= self.retention
+                    })
+                })
+                .map(|job| job.info.id.clone())
+                .collect();
+            for id in &expired {
+                jobs.remove(id);
+            }
+            expired
+        };
+        for id in expired {
+            let _ = tokio::fs::remove_file(self.dir.join(&id)).await;
+            tracing::debug!("removed expired job {}", id);
+        }
+    }
+}
+
+/// Remove expired jobs periodically until `shutdown` is cancelled.
+pub fn spawn_retention(jobs: Arc<JobStore>, shutdown: CancellationToken) {
+    let period = jobs
+        .retention
+        .clamp(Duration::from_secs(1), RETENTION_SWEEP);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => jobs.remove_expired().await,
+            }
+        }
+    });
+}
+
+fn job_store(state: &ServerState) -> Result<Arc<JobStore>> {
+    state
+        .jobs
+        .clone()
+        .ok_or_else(|| eyre!("report jobs are disabled").into())
+}
+
+/// Render `request` like `POST /api/report`, waiting up to the queue timeout
+/// for room in a full queue instead of failing.
+async fn render_job(
+    state: &ServerState,
+    jobs: &Arc<JobStore>,
+    id: &str,
+    request: RenderRequest,
+) -> Result<CachedReport> {
+    retry_busy(jobs.queue_timeout, || {
+        let on_start = {
+            let jobs = Arc::clone(jobs);
+            let id = id.to_string();
+            move || jobs.set_running(&id)
+        };
+        render_packed(
+            Arc::clone(&state.render_pool),
+            Arc::clone(&state.typst.load().renderer),
+            request.source.clone(),
+            request.theme.clone(),
+            request.options.clone(),
+            request.bundle,
+            on_start,
+        )
+    })
+    .await
+}
+
+async fn run_job(
+    state: ServerState,
+    jobs: Arc<JobStore>,
+    id: String,
+    user: String,
+    request: RenderRequest,
+    callback: Option<Callback>,
+    cancel: CancellationToken,
+) {
+    let in_flight = state.in_flight.enter();
+    let (name, theme) = (request.name.clone(), request.theme.clone());
+    let result = tokio::select! {
+        _ = cancel.cancelled() => {
+            tracing::info!("job {} cancelled", id);
+            return;
+        }
+        result = render_job(&state, &jobs, &id, request) => result,
+    };
+    if let Ok(report) = &result {
+        archive::record(&state, &user, &theme, &name, report);
+    }
+    let Some(info) = jobs.finish(&id, result).await else {
+        return;
+    };
+    drop(in_flight);
+    tracing::info!("job {} {}", id, info.status);
+    if let Some(callback) = callback {
+        let notification = JobNotification::new(&info, jobs.public_url.as_deref());
+        jobs.notifier.notify(&callback, &notification).await;
+    }
+}
+
+/// Body of `POST /api/reports/jobs`: a report plus where to report back.
+#[derive(Debug, Deserialize)]
+pub struct JobRequest {
+    #[serde(flatten)]
+    pub report: ReportRequest,
+    /// Receives a [`JobNotification`] once the job finished.
+    pub callback_url: Option<String>,
+    /// Signs the notification with HMAC-SHA256 when set.
+    pub callback_secret: Option<String>,
+}
+
+impl JobRequest {
+    /// The callback of the job, its host one of `hosts`.
+    fn callback(&self, hosts: &[String]) -> Result<Option<Callback>> {
+        let Some(url) = &self.callback_url else {
+            return Ok(None);
+        };
+        let url: reqwest::Url = url.parse().map_err(|e| {
+            InvalidInputSnafu {
+                reason: format!("Invalid callback_url: {e}"),
+            }
+            .build()
+        })?;
+        ensure!(
+            matches!(url.scheme(), "http" | "https"),
+            InvalidInputSnafu {
+                reason: "callback_url must be an http or https URL",
+            }
+        );
+        let host = url.host_str().unwrap_or_default();
+        ensure!(
+            webhook::host_allowed(hosts, host),
+            InvalidInputSnafu {
+                reason: format!("callback_url host {host} is not allowed"),
+            }
+        );
+        Ok(Some(Callback {
+            url,
+            secret: self.callback_secret.clone(),
+        }))
+    }
+}
+
+#[tracing::instrument(name = "create_job", skip(state, permit, payload), fields(user = %auth_info.user_id))]
+pub async fn create_job(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    permit: Option<Extension<Arc<RenderPermit>>>,
+    ValidatedJson(payload): ValidatedJson<JobRequest>,
+) -> Result<Response> {
+    let jobs = job_store(&state)?;
+    let callback = payload.callback(&jobs.callback_hosts)?;
+    let request = payload.report.into_render()?;
+    state
+        .authorizer
+        .authorize_theme(&auth_info, &request.theme)?;
+    let (info, cancel) = jobs.create(
+        &auth_info.user_id,
+        &request.name,
+        &request.theme,
+        permit.map(|Extension(permit)| permit),
+    )?;
+    tokio::spawn(run_job(
+        state,
+        Arc::clone(&jobs),
+        info.id.clone(),
+        auth_info.user_id.clone(),
+        request,
+        callback,
+        cancel,
+    ));
+    let location = format!("/api/reports/jobs/{}", info.id);
+    Ok((StatusCode::ACCEPTED, [(LOCATION, location)], Json(info)).into_response())
+}
+
+pub async fn job_status(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    Path(id): Path<String>,
+) -> Result<Json<JobInfo>> {
+    Ok(Json(job_store(&state)?.get(&auth_info.user_id, &id)?))
+}
+
+pub async fn job_file(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    Path(id): Path<String>,
+) -> Result<Response> {
+    let (info, report) = job_store(&state)?.file(&auth_info.user_id, &id).await?;
+    Ok(report_response(&info.name, &report))
+}
+
+pub async fn cancel_job(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    Path(id): Path<String>,
+) -> Result<StatusCode> {
+    job_store(&state)?.cancel(&auth_info.user_id, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(retention_secs: u64) -> (JobStore, PathBuf) {
+        static DIRS: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "jobs-test-{}-{}",
+            process::id(),
+            DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        let config = JobsConfig {
+            dir: dir.display().to_string(),
+            retention_secs,
+            public_url: None,
+            callback_retries: 0,
+            callback_backoff_ms: 10,
+            callback_hosts: vec![],
+            max_per_user: 10,
+            queue_timeout_secs: 60,
+        };
+        (JobStore::new(&config).unwrap(), dir)
+    }
+
+    fn pdf() -> CachedReport {
+        CachedReport {
+            content_type: "application/pdf".to_string(),
+            ext: Some(".pdf".to_string()),
+            body: Bytes::from_static(b"%PDF"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_lifecycle() {
+        let (jobs, dir) = store(60);
+        let (info, _) = jobs.create("alice", "weekly", "default", None).unwrap();
+        assert_eq!(info.status, JobStatus::Queued);
+        assert!(matches!(
+            jobs.get("bob", &info.id),
+            Err(Error::JobNotFound { .. })
+        ));
+        assert!(matches!(
+            jobs.file("alice", &info.id).await,
+            Err(Error::JobNotDone { .. })
+        ));
+
+        jobs.set_running(&info.id);
+        assert_eq!(
+            jobs.get("alice", &info.id).unwrap().status,
+            JobStatus::Running
+        );
+        let done = jobs.finish(&info.id, Ok(pdf())).await.unwrap();
+        assert_eq!(done.status, JobStatus::Done);
+        assert_eq!(done.size, Some(4));
+        assert_eq!(
+            done.sha256.as_deref(),
+            Some(format!("{:x}", Sha256::digest(b"%PDF")).as_str())
+        );
+        let (_, report) = jobs.file("alice", &info.id).await.unwrap();
+        assert_eq!(report.body, "%PDF");
+
+        jobs.cancel("alice", &info.id).await.unwrap();
+        assert!(!dir.join(&info.id).exists());
+        assert!(jobs.get("alice", &info.id).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unfinished_jobs_per_user_are_capped() {
+        let (jobs, dir) = store(60);
+        let (first, _) = jobs.create("alice", "weekly", "default", None).unwrap();
+        for _ in 1..10 {
+            jobs.create("alice", "weekly", "default", None).unwrap();
+        }
+        assert!(matches!(
+            jobs.create("alice", "weekly", "default", None),
+            Err(Error::TooManyJobs { limit: 10 })
+        ));
+        assert!(jobs.create("bob", "weekly", "default", None).is_ok());
+        jobs.finish(&first.id, Ok(pdf())).await.unwrap();
+        assert!(jobs.create("alice", "weekly", "default", None).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_job_is_not_stored() {
+        let (jobs, dir) = store(60);
+        let (info, cancel) = jobs.create("alice", "weekly", "default", None).unwrap();
+        jobs.cancel("alice", &info.id).await.unwrap();
+        assert!(cancel.is_cancelled());
+        assert!(jobs.finish(&info.id, Ok(pdf())).await.is_none());
+        assert!(!dir.join(&info.id).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_jobs_are_removed() {
+        let (jobs, dir) = store(0);
+        let (done, _) = jobs.create("alice", "weekly", "default", None).unwrap();
+        let (queued, _) = jobs.create("alice", "weekly", "default", None).unwrap();
+        jobs.finish(&done.id, Ok(pdf())).await.unwrap();
+        jobs.remove_expired().await;
+        assert!(jobs.get("alice", &done.id).is_err());
+        assert!(!dir.join(&done.id).exists());
+        assert!(jobs.get("alice", &queued.id).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
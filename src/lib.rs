@@ -1,10 +1,27 @@
+pub mod archive;
 pub mod auth;
+pub mod authz;
+pub mod batch;
+pub mod cache;
+pub mod cli;
 pub mod client_config;
 pub mod config;
 pub mod error;
 pub mod extractor;
+pub mod health;
+pub mod jobs;
+pub mod metrics;
+pub mod rate_limit;
+pub mod render_pool;
 pub mod run;
+pub mod s3;
 pub mod server;
+pub mod shutdown;
+pub mod storage;
+pub mod tls;
+pub mod token_review;
 pub mod typst_lib;
+pub mod validate;
+pub mod webhook;
 
 pub use run::run;
@@ -0,0 +1,221 @@
+use std::{sync::LazyLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderValue, header::CONTENT_TYPE},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+    exponential_buckets,
+};
+
+use crate::cache::CacheStatus;
+
+/// Collectors of the whole process, registered once.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    render_duration: HistogramVec,
+    render_size: HistogramVec,
+    render_failures: IntCounterVec,
+    auth_failures: IntCounterVec,
+    config_reloads: IntCounterVec,
+    typst_reloads: IntCounterVec,
+    cache_lookups: IntCounterVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("kube_eye_export".to_string()), None)
+            .expect("valid metrics prefix");
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency by route and status",
+            )
+            .buckets(exponential_buckets(0.005, 2.0, 14).unwrap()),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let render_duration = HistogramVec::new(
+            HistogramOpts::new("render_duration_seconds", "Report render time by theme")
+                .buckets(exponential_buckets(0.05, 2.0, 12).unwrap()),
+            &["theme", "format"],
+        )
+        .unwrap();
+        let render_size = HistogramVec::new(
+            HistogramOpts::new("render_size_bytes", "Rendered report size by theme")
+                .buckets(exponential_buckets(16.0 * 1024.0, 2.0, 12).unwrap()),
+            &["theme", "format"],
+        )
+        .unwrap();
+        let render_failures = IntCounterVec::new(
+            Opts::new("render_failures_total", "Failed renders by theme and type"),
+            &["theme", "kind"],
+        )
+        .unwrap();
+        let auth_failures = IntCounterVec::new(
+            Opts::new("auth_failures_total", "Rejected API requests by reason"),
+            &["reason"],
+        )
+        .unwrap();
+        let config_reloads = IntCounterVec::new(
+            Opts::new(
+                "client_config_reloads_total",
+                "Client config reloads by result",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let typst_reloads = IntCounterVec::new(
+            Opts::new(
+                "typst_config_reloads_total",
+                "Typst config reloads by result",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("render_cache_lookups_total", "Render cache lookups by result"),
+            &["result"],
+        )
+        .unwrap();
+        for collector in [
+            &http_requests,
+            &render_failures,
+            &auth_failures,
+            &config_reloads,
+            &typst_reloads,
+            &cache_lookups,
+        ] {
+            registry.register(Box::new(collector.clone())).unwrap();
+        }
+        for collector in [&http_duration, &render_duration, &render_size] {
+            registry.register(Box::new(collector.clone())).unwrap();
+        }
+        Self {
+            registry,
+            http_requests,
+            http_duration,
+            render_duration,
+            render_size,
+            render_failures,
+            auth_failures,
+            config_reloads,
+            typst_reloads,
+            cache_lookups,
+        }
+    }
+
+    pub fn observe_render(&self, theme: &str, format: &str, started: Instant, size: usize) {
+        self.render_duration
+            .with_label_values(&[theme, format])
+            .observe(started.elapsed().as_secs_f64());
+        self.render_size
+            .with_label_values(&[theme, format])
+            .observe(size as f64);
+    }
+
+    pub fn render_failed(&self, theme: &str, kind: &str) {
+        self.render_failures.with_label_values(&[theme, kind]).inc();
+    }
+
+    pub fn auth_failed(&self, reason: &str) {
+        self.auth_failures.with_label_values(&[reason]).inc();
+    }
+
+    pub fn config_reloaded(&self, success: bool) {
+        self.config_reloads
+            .with_label_values(&[reload_result(success)])
+            .inc();
+    }
+
+    pub fn cache_lookup(&self, status: CacheStatus) {
+        self.cache_lookups
+            .with_label_values(&[status.as_str()])
+            .inc();
+    }
+
+    pub fn typst_config_reloaded(&self, success: bool) {
+        self.typst_reloads
+            .with_label_values(&[reload_result(success)])
+            .inc();
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("encode metrics error: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+fn reload_result(success: bool) -> &'static str {
+    if success { "success" } else { "failure" }
+}
+
+/// Count and time every request by its route template.
+pub async fn track_requests(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let method = req.method().to_string();
+    let started = Instant::now();
+    let response = next.run(req).await;
+    let status = response.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    let metrics = metrics();
+    metrics.http_requests.with_label_values(&labels).inc();
+    metrics
+        .http_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+pub async fn metrics_handler() -> impl IntoResponse {
+    (
+        [(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        metrics().encode(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_metrics() {
+        let metrics = metrics();
+        metrics.auth_failed("test_reason");
+        metrics.observe_render("test_theme", "pdf", Instant::now(), 2048);
+        metrics.config_reloaded(false);
+        let text = metrics.encode();
+        assert!(text.contains(r#"kube_eye_export_auth_failures_total{reason="test_reason"}"#));
+        assert!(text.contains(
+            r#"kube_eye_export_render_size_bytes_count{format="pdf",theme="test_theme"} 1"#
+        ));
+        assert!(text.contains(r#"kube_eye_export_client_config_reloads_total{result="failure"}"#));
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    auth::AuthInfo,
+    config::RateLimitConfig,
+    error::{Error as ApiError, Result},
+};
+
+pub const RATE_LIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+pub const RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+pub const RENDERS_REMAINING: HeaderName = HeaderName::from_static("x-renders-remaining");
+
+/// `Retry-After` sent when a client has too many renders in flight.
+const RENDER_RETRY_AFTER_SECS: u64 = 1;
+
+/// Idle buckets are pruned once the map grows past this size.
+const PRUNE_SIZE: usize = 4096;
+
+/// Per-client token buckets and in-flight render counters.
+pub struct RateLimiter {
+    burst: f64,
+    /// Tokens added back per second.
+    rate: f64,
+    max_concurrent_renders: usize,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    renders: Mutex<HashMap<String, usize>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Quota left to a client after an admitted request.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    pub remaining: u32,
+    pub renders_remaining: usize,
+}
+
+/// A render slot of one client, released on drop.
+///
+/// The middleware holds it until the response is ready and also hands it to
+/// the handler as an `Extension<Arc<RenderPermit>>`, so work the handler
+/// spawns keeps the slot until it is done.
+pub struct RenderPermit {
+    limiter: Arc<RateLimiter>,
+    key: String,
+}
+
+impl Drop for RenderPermit {
+    fn drop(&mut self) {
+        let mut renders = self
+            .limiter
+            .renders
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = renders.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                renders.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            burst: f64::from(config.burst.max(1)),
+            rate: f64::from(config.per_minute.max(1)) / 60.0,
+            max_concurrent_renders: config.max_concurrent_renders.max(1),
+            buckets: Mutex::new(HashMap::new()),
+            renders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the bucket of `key`.
+    fn take(&self, key: &str) -> Result<u32> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if buckets.len() >= PRUNE_SIZE {
+            let full_after = Duration::from_secs_f64(self.burst / self.rate);
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < full_after);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            let retry_after = ((1.0 - bucket.tokens) / self.rate).ceil() as u64;
+            return Err(ApiError::RateLimited {
+                retry_after: retry_after.max(1),
+            });
+        }
+        bucket.tokens -= 1.0;
+        Ok(bucket.tokens.floor() as u32)
+    }
+
+    /// Claim a render slot of `key`.
+    fn acquire(self: &Arc<Self>, key: &str) -> Result<(RenderPermit, usize)> {
+        let mut renders = self.renders.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = renders.entry(key.to_string()).or_default();
+        if *count >= self.max_concurrent_renders {
+            return Err(ApiError::TooManyRenders {
+                limit: self.max_concurrent_renders,
+                retry_after: RENDER_RETRY_AFTER_SECS,
+            });
+        }
+        *count += 1;
+        let permit = RenderPermit {
+            limiter: Arc::clone(self),
+            key: key.to_string(),
+        };
+        Ok((permit, self.max_concurrent_renders - *count))
+    }
+
+    /// Admit a request of `key`, returning its render slot and what is left.
+    pub fn check(self: &Arc<Self>, key: &str) -> Result<(RenderPermit, Quota)> {
+        let (permit, renders_remaining) = self.acquire(key)?;
+        let remaining = self.take(key)?;
+        Ok((
+            permit,
+            Quota {
+                remaining,
+                renders_remaining,
+            },
+        ))
+    }
+
+    fn headers(&self, quota: Quota) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(self.burst as u32));
+        headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from(quota.remaining));
+        headers.insert(
+            RENDERS_REMAINING,
+            HeaderValue::from(quota.renders_remaining),
+        );
+        headers
+    }
+}
+
+/// Identity the requests of an authenticated user are limited by.
+pub fn user_key(user_id: &str) -> String {
+    format!("user:{user_id}")
+}
+
+/// Identity a request is limited by: its user, else its peer address.
+fn client_key(req: &Request) -> String {
+    if let Some(auth_info) = req.extensions().get::<AuthInfo>() {
+        return user_key(&auth_info.user_id);
+    }
+    match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+        None => "anonymous".to_string(),
+    }
+}
+
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let key = client_key(&req);
+    match limiter.check(&key) {
+        Ok((permit, quota)) => {
+            let permit = Arc::new(permit);
+            req.extensions_mut().insert(Arc::clone(&permit));
+            let mut response = next.run(req).await;
+            drop(permit);
+            response.headers_mut().extend(limiter.headers(quota));
+            response
+        }
+        Err(e) => {
+            tracing::info!("throttled {key}: {e}");
+            let quota = Quota {
+                remaining: 0,
+                renders_remaining: 0,
+            };
+            (limiter.headers(quota), e).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Extension, Router,
+        body::Body,
+        http::{StatusCode, header::RETRY_AFTER},
+        middleware,
+        routing::get,
+    };
+    use tokio::sync::oneshot;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn limiter(burst: u32, max_concurrent_renders: usize) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter::new(&RateLimitConfig {
+            burst,
+            per_minute: 60,
+            max_concurrent_renders,
+        }))
+    }
+
+    fn request(user_id: &str) -> Request {
+        let mut req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        req.extensions_mut().insert(AuthInfo {
+            user_id: user_id.to_string(),
+            groups: vec![],
+        });
+        req
+    }
+
+    #[test]
+    fn test_bucket_exhausted() {
+        let limiter = limiter(2, 4);
+        assert_eq!(limiter.check("a").unwrap().1.remaining, 1);
+        assert_eq!(limiter.check("a").unwrap().1.remaining, 0);
+        assert!(matches!(
+            limiter.check("a"),
+            Err(ApiError::RateLimited { retry_after: 1 })
+        ));
+        assert!(limiter.check("b").is_ok());
+    }
+
+    #[test]
+    fn test_render_permit_released_on_drop() {
+        let limiter = limiter(10, 1);
+        let (permit, quota) = limiter.check("a").unwrap();
+        assert_eq!(quota.renders_remaining, 0);
+        assert!(matches!(
+            limiter.check("a"),
+            Err(ApiError::TooManyRenders { limit: 1, .. })
+        ));
+        drop(permit);
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.renders.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_middleware() {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(limiter(1, 1), rate_limit));
+
+        let response = app.clone().oneshot(request("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(RATE_LIMIT_LIMIT).unwrap(), "1");
+        assert_eq!(response.headers().get(RATE_LIMIT_REMAINING).unwrap(), "0");
+
+        let response = app.clone().oneshot(request("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().get(RETRY_AFTER).is_some());
+
+        let response = app.oneshot(request("bob")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Signals the first request has started, then waits to be released.
+    type Gate = Arc<Mutex<Option<(oneshot::Sender<()>, oneshot::Receiver<()>)>>>;
+
+    #[tokio::test]
+    async fn test_rate_limit_middleware_concurrent_renders() {
+        let (started_tx, started_rx) = oneshot::channel::<()>();
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let gate: Gate = Arc::new(Mutex::new(Some((started_tx, release_rx))));
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(gate): Extension<Gate>| async move {
+                    let channels = gate.lock().unwrap().take();
+                    if let Some((started, release)) = channels {
+                        started.send(()).unwrap();
+                        release.await.unwrap();
+                    }
+                    "ok"
+                }),
+            )
+            .layer(Extension(gate))
+            .layer(middleware::from_fn_with_state(limiter(10, 1), rate_limit));
+
+        let slow = tokio::spawn(app.clone().oneshot(request("alice")));
+        started_rx.await.unwrap();
+        let response = app.clone().oneshot(request("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        release_tx.send(()).unwrap();
+        assert_eq!(slow.await.unwrap().unwrap().status(), StatusCode::OK);
+        let response = app.oneshot(request("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_middleware_spawned_render_keeps_slot() {
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let release = Arc::new(Mutex::new(Some(release_rx)));
+        let app = Router::new()
+            .route(
+                "/",
+                get(
+                    |Extension(permit): Extension<Arc<RenderPermit>>| async move {
+                        let release = release.lock().unwrap().take();
+                        tokio::spawn(async move {
+                            if let Some(release) = release {
+                                let _ = release.await;
+                            }
+                            drop(permit);
+                        });
+                        "accepted"
+                    },
+                ),
+            )
+            .layer(middleware::from_fn_with_state(limiter(10, 1), rate_limit));
+
+        let response = app.clone().oneshot(request("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(request("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        release_tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = app.oneshot(request("alice")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,273 @@
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+    time::Duration,
+};
+
+use tokio::{sync::oneshot, time::Instant};
+
+use crate::{
+    config::RenderConfig,
+    error::{Error, Result},
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Dedicated threads for CPU-heavy typst compilation, so renders never block
+/// the async runtime.
+///
+/// A render that times out keeps its worker busy until typst returns, since
+/// compilation cannot be interrupted; only the waiting request is released.
+/// Renders nobody waits for anymore are skipped while still queued.
+pub struct RenderPool {
+    sender: SyncSender<Job>,
+    workers: usize,
+    timeout: Duration,
+    retry_after: u64,
+}
+
+impl RenderPool {
+    pub fn new(config: &RenderConfig) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(config.queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..config.workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("typst-render-{index}"))
+                .spawn(move || worker(receiver))
+                .expect("failed to spawn render worker");
+        }
+        Self {
+            sender,
+            workers: config.workers.max(1),
+            timeout: Duration::from_secs(config.timeout_secs),
+            retry_after: config.retry_after_secs,
+        }
+    }
+
+    /// Number of renders running at once.
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Queue `job` on the pool and wait for its result.
+    pub async fn run<F, T>(&self, job: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            if tx.is_closed() {
+                return;
+            }
+            let _ = tx.send(job());
+        });
+        self.sender.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => Error::RenderQueueFull {
+                retry_after: self.retry_after,
+            },
+            TrySendError::Disconnected(_) => Error::Internal {
+                source: color_eyre::eyre::eyre!("render pool is shut down"),
+            },
+        })?;
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(Error::Internal {
+                source: color_eyre::eyre::eyre!("render worker panicked"),
+            }),
+            Err(_) => Err(Error::RenderTimeout {
+                timeout_secs: self.timeout.as_secs(),
+            }),
+        }
+    }
+}
+
+/// Run `attempt` again after its `Retry-After` while the pool or the quota of
+/// the client is busy, failing with the last error once `wait` has passed.
+pub async fn retry_busy<F, Fut, T>(wait: Duration, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let deadline = Instant::now() + wait;
+    loop {
+        let error = match attempt().await {
+            Err(error) => error,
+            result => return result,
+        };
+        let Some(retry_after) = error.retry_after() else {
+            return Err(error);
+        };
+        let pause = Duration::from_secs(retry_after.max(1));
+        if Instant::now() + pause > deadline {
+            return Err(error);
+        }
+        tokio::time::sleep(pause).await;
+    }
+}
+
+fn worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        let Ok(job) = job else {
+            return;
+        };
+        // A panicking render fails only its own request, the worker lives on.
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            tracing::error!("render job panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    fn pool(workers: usize, queue_size: usize, timeout_secs: u64) -> RenderPool {
+        RenderPool::new(&RenderConfig {
+            workers,
+            queue_size,
+            timeout_secs,
+            retry_after_secs: 7,
+            cache: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_render_pool_returns_job_result() {
+        let pool = pool(1, 1, 5);
+        let result = pool.run(|| Ok(42)).await.unwrap();
+        assert_eq!(result, 42);
+    }
+
+    #[tokio::test]
+    async fn test_render_pool_propagates_job_error() {
+        let pool = pool(1, 1, 5);
+        let result: Result<()> = pool
+            .run(|| {
+                Err(Error::BadRequest {
+                    message: "test".to_string(),
+                })
+            })
+            .await;
+        assert!(matches!(result, Err(Error::BadRequest { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_render_pool_survives_panicking_job() {
+        let pool = pool(1, 1, 5);
+        for _ in 0..2 {
+            let result: Result<()> = pool.run(|| panic!("render failed")).await;
+            assert!(matches!(result, Err(Error::Internal { .. })));
+        }
+        assert_eq!(pool.run(|| Ok(42)).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_retry_busy() {
+        let mut attempts = 0;
+        let result = retry_busy(Duration::from_secs(5), || {
+            attempts += 1;
+            let result = match attempts {
+                1 => Err(Error::RenderQueueFull { retry_after: 1 }),
+                _ => Ok(attempts),
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+
+        let result: Result<()> = retry_busy(Duration::ZERO, || async {
+            Err(Error::TooManyRenders {
+                limit: 1,
+                retry_after: 1,
+            })
+        })
+        .await;
+        assert!(matches!(result, Err(Error::TooManyRenders { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_render_pool_timeout() {
+        let pool = pool(1, 1, 0);
+        let result = pool
+            .run(|| {
+                thread::sleep(Duration::from_millis(200));
+                Ok(())
+            })
+            .await;
+        assert!(matches!(result, Err(Error::RenderTimeout { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_render_pool_skips_abandoned_job() {
+        let pool = pool(1, 1, 0);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.sender
+            .try_send(Box::new(move || {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.recv();
+            }))
+            .unwrap();
+        started_rx.recv().unwrap();
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let result = pool
+            .run({
+                let ran = Arc::clone(&ran);
+                move || {
+                    ran.store(true, Ordering::SeqCst);
+                    Ok(())
+                }
+            })
+            .await;
+        assert!(matches!(result, Err(Error::RenderTimeout { .. })));
+
+        let (done_tx, done_rx) = mpsc::channel();
+        release_tx.send(()).unwrap();
+        pool.sender
+            .send(Box::new(move || done_tx.send(()).unwrap()))
+            .unwrap();
+        done_rx.recv().unwrap();
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_render_pool_queue_full() {
+        let pool = Arc::new(pool(1, 1, 5));
+        let (started_tx, started_rx) = oneshot::channel();
+        let busy = {
+            let pool = Arc::clone(&pool);
+            tokio::spawn(async move {
+                pool.run(move || {
+                    started_tx.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(300));
+                    Ok(())
+                })
+                .await
+            })
+        };
+        started_rx.await.unwrap();
+        // The worker is busy, so this fills the only queue slot.
+        assert!(pool.sender.try_send(Box::new(|| ())).is_ok());
+
+        let result = pool.run(|| Ok(())).await;
+        match result {
+            Err(Error::RenderQueueFull { retry_after }) => assert_eq!(retry_after, 7),
+            _ => panic!("Expected RenderQueueFull error"),
+        }
+        busy.await.unwrap().unwrap();
+    }
+}
@@ -1,62 +1,215 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use arc_swap::ArcSwap;
-use figment::{Figment, providers::Format};
-use notify::{Watcher, recommended_watcher};
+use figment::{
+    Figment,
+    providers::{Format, Serialized},
+};
+use notify::{EventKind, RecursiveMode, Watcher, recommended_watcher};
 use snafu::ResultExt;
 use tokio::sync::mpsc;
 
 use crate::{
+    archive,
+    cli::{Cli, Command, ConfigArgs, RenderArgs},
     client_config::ClientConfig,
     config::Config,
-    error::{self, FigmentParseSnafu, WatchFileSnafu},
-    server,
+    error::{
+        self, ConfigParseSnafu, Error, FigmentParseSnafu, FileIoSnafu, InvalidConfigSnafu,
+        TomlSerializeSnafu, WatchFileSnafu,
+    },
+    jobs,
+    metrics::metrics,
+    server::{self, ServerState},
+    storage,
+    typst_lib::{Entry, ReportSource, Renderer, generate_pdf},
+    validate,
 };
 
-async fn load_server_config() -> error::Result<Config> {
-    let config: Config = Figment::new()
-        .merge(figment::providers::Toml::file(
-            "/etc/kube-eye-export-server/Config.toml",
-        ))
-        .merge(figment::providers::Toml::file("Config.toml"))
-        .merge(figment::providers::Toml::file("configs/Config.toml"))
-        .merge(figment::providers::Env::prefixed("APP_"))
+/// Server config files, later ones overriding earlier ones.
+const SERVER_CONFIG_FILES: [&str; 3] = [
+    "/etc/kube-eye-export-server/Config.toml",
+    "Config.toml",
+    "configs/Config.toml",
+];
+
+/// Client config files, later ones overriding earlier ones.
+const CLIENT_CONFIG_FILES: [&str; 3] = [
+    "/etc/kube-eye-export-server/client_config.yaml",
+    "configs/client_config.yaml",
+    "configs/local_client_config.yaml",
+];
+
+/// Wait for more events of the same save before reloading.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+
+fn server_config_files(args: &ConfigArgs) -> Vec<PathBuf> {
+    match &args.config {
+        Some(file) => vec![file.clone()],
+        None => SERVER_CONFIG_FILES.iter().map(PathBuf::from).collect(),
+    }
+}
+
+fn client_config_files(args: &ConfigArgs) -> Vec<PathBuf> {
+    if args.client_config.is_empty() {
+        CLIENT_CONFIG_FILES.iter().map(PathBuf::from).collect()
+    } else {
+        args.client_config.clone()
+    }
+}
+
+/// Directory to watch for changes of `file`.
+pub(crate) fn parent_dir(file: &Path) -> PathBuf {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+async fn load_server_config(args: &ConfigArgs) -> error::Result<Config> {
+    let config: Config = server_config_figment(args)
         .extract()
         .context(FigmentParseSnafu)?;
     Ok(config)
 }
 
-pub async fn load_client_config() -> error::Result<ClientConfig> {
-    let config: ClientConfig = client_config_figment()
+fn server_config_figment(args: &ConfigArgs) -> Figment {
+    let mut figment = server_config_files(args)
+        .iter()
+        .fold(Figment::new(), |figment, file| {
+            figment.merge(figment::providers::Toml::file(file))
+        })
+        .merge(figment::providers::Env::prefixed("APP_"));
+    if let Some(host) = &args.host {
+        figment = figment.merge(Serialized::default("server.host", host));
+    }
+    if let Some(port) = args.port {
+        figment = figment.merge(Serialized::default("server.port", port));
+    }
+    if let Some(assets_dir) = &args.assets_dir {
+        figment = figment.merge(Serialized::default("typst.assets_dir", assets_dir));
+    }
+    figment
+}
+
+pub async fn load_client_config(args: &ConfigArgs) -> error::Result<ClientConfig> {
+    let config: ClientConfig = client_config_figment(args)
         .extract()
         .context(FigmentParseSnafu)?;
     Ok(config)
 }
 
-fn client_config_figment() -> Figment {
-    Figment::new()
-        .merge(figment::providers::Yaml::file(
-            "/etc/kube-eye-export-server/client_config.yaml",
-        ))
-        .merge(figment::providers::Yaml::file("configs/client_config.yaml"))
-        .merge(figment::providers::Yaml::file("configs/local_client_config.yaml"))
+fn client_config_figment(args: &ConfigArgs) -> Figment {
+    client_config_files(args)
+        .iter()
+        .fold(Figment::new(), |figment, file| {
+            figment.merge(figment::providers::Yaml::file(file))
+        })
+}
+
+pub async fn run(cli: Cli) -> error::Result<()> {
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(cli.config).await,
+        Command::CheckConfig => check_config(&cli.config).await,
+        Command::PrintConfig => print_config(&cli.config).await,
+        Command::Render(args) => render(&cli.config, args).await,
+    }
 }
 
-pub async fn run() -> error::Result<()> {
-    let config: Config = load_server_config().await?;
-    let client_config: ClientConfig = load_client_config().await?;
+async fn serve(args: ConfigArgs) -> error::Result<()> {
+    let config: Config = load_server_config(&args).await?;
+    storage::sync_assets(&config.typst).await?;
+    validate::validate(&config.typst)?;
+    let client_config: ClientConfig = load_client_config(&args).await?;
     tracing::info!("get client config: {:#?}", &client_config);
     let client_config = Arc::new(ArcSwap::from_pointee(client_config));
-    let path = PathBuf::from("configs");
-    spawn_config_watcher(path, Arc::clone(&client_config)).await?;
     let server = server::Server::new(config.server, client_config);
-    server.run(config.typst).await
+    let state = ServerState::new(
+        &server.config,
+        Arc::clone(&server.client_config),
+        config.typst,
+    )?;
+    spawn_config_watcher(args.clone(), state.clone()).await?;
+    spawn_typst_watcher(args, state.clone()).await?;
+    if let Some(jobs) = &state.jobs {
+        jobs::spawn_retention(Arc::clone(jobs), state.shutdown.clone());
+    }
+    if let Some(archive) = &state.archive {
+        archive.load().await?;
+        archive::spawn_retention(Arc::clone(archive), state.shutdown.clone());
+    }
+    server.serve(state).await
+}
+
+/// Report every problem of the configs, failing if there is any.
+async fn check_config(args: &ConfigArgs) -> error::Result<()> {
+    let config: Config = load_server_config(args).await?;
+    load_client_config(args).await?;
+    storage::sync_assets(&config.typst).await?;
+    let problems = validate::config_problems(&config.typst);
+    if !problems.is_empty() {
+        return InvalidConfigSnafu { problems }.fail();
+    }
+    println!("config ok");
+    Ok(())
+}
+
+/// Render one report with the configured themes, printing compile
+/// diagnostics to stderr.
+async fn render(args: &ConfigArgs, render: RenderArgs) -> error::Result<()> {
+    let config: Config = load_server_config(args).await?;
+    storage::sync_assets(&config.typst).await?;
+    validate::validate(&config.typst)?;
+    let entry = match (render.input, render.template) {
+        (Some(input), None) => {
+            Entry::Content(tokio::fs::read_to_string(input).await.context(FileIoSnafu)?)
+        }
+        (None, Some(template)) => Entry::Template(template),
+        _ => unreachable!("clap requires exactly one of input and template"),
+    };
+    let data = match render.data {
+        Some(path) => {
+            let json = tokio::fs::read(path).await.context(FileIoSnafu)?;
+            Some(serde_json::from_slice(&json).context(ConfigParseSnafu)?)
+        }
+        None => None,
+    };
+    let source = ReportSource { entry, data };
+    let renderer = Renderer::new(&config.typst);
+    let result = tokio::task::spawn_blocking(move || {
+        generate_pdf(source, &renderer, &render.theme)
+    })
+    .await
+    .map_err(|e| color_eyre::eyre::eyre!("render panicked: {e}"))?;
+    let pdf = match result {
+        Ok(pdf) => pdf,
+        Err(Error::TypstCompile { diagnostics }) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{diagnostic}");
+            }
+            return Err(Error::TypstCompile { diagnostics });
+        }
+        Err(e) => return Err(e),
+    };
+    tokio::fs::write(&render.out, pdf).await.context(FileIoSnafu)?;
+    tracing::info!("rendered {}", render.out.display());
+    Ok(())
+}
+
+async fn print_config(args: &ConfigArgs) -> error::Result<()> {
+    let config: Config = load_server_config(args).await?;
+    let config = config.redacted();
+    print!("{}", toml::to_string_pretty(&config).context(TomlSerializeSnafu)?);
+    Ok(())
 }
 
-pub async fn spawn_config_watcher(
-    path: PathBuf,
-    client_config: Arc<ArcSwap<ClientConfig>>,
-) -> error::Result<()> {
+/// Reload the client config on changes until the server shuts down,
+/// recording whether the last reload failed.
+pub async fn spawn_config_watcher(args: ConfigArgs, state: ServerState) -> error::Result<()> {
     tokio::spawn(async move {
         let (tx, mut rx) = mpsc::channel(1);
         let mut watcher = recommended_watcher(move |res| {
@@ -67,32 +220,156 @@ pub async fn spawn_config_watcher(
         })
         .context(WatchFileSnafu)?;
 
-        if path.exists() {
-            watcher
-                .watch(path.as_path(), notify::RecursiveMode::NonRecursive)
-                .context(WatchFileSnafu)?;
-        } else {
-            tracing::warn!(
-                "skip watching missing client config file or dir: {}",
-                path.display()
-            );
+        let mut dirs: Vec<PathBuf> = client_config_files(&args)
+            .iter()
+            .map(|file| parent_dir(file))
+            .collect();
+        dirs.dedup();
+        for path in dirs {
+            if path.exists() {
+                watcher
+                    .watch(path.as_path(), notify::RecursiveMode::NonRecursive)
+                    .context(WatchFileSnafu)?;
+            } else {
+                tracing::warn!(
+                    "skip watching missing client config file or dir: {}",
+                    path.display()
+                );
+            }
         }
 
-        while let Some(event) = rx.recv().await {
+        while let Some(event) = tokio::select! {
+            _ = state.shutdown.cancelled() => None,
+            event = rx.recv() => event,
+        } {
             tracing::info!("event: {:#?}", event);
-            client_config_figment()
+            client_config_figment(&args)
                 .extract()
                 .context(FigmentParseSnafu)
                 .map_or_else(
                     |e| {
                         tracing::error!("get new config error: {}", e);
+                        state.client_config_error.store(Some(Arc::new(e.to_string())));
+                        metrics().config_reloaded(false);
                     },
                     |config| {
                         tracing::info!("get new config: {:#?}", &config);
-                        client_config.store(Arc::new(config));
+                        state.client_config.store(Arc::new(config));
+                        state.client_config_error.store(None);
+                        metrics().config_reloaded(true);
                     },
                 );
         }
+        tracing::info!("stopped watching client config");
+        Ok::<(), error::Error>(())
+    });
+    Ok(())
+}
+
+/// Paths whose changes may affect the typst config.
+fn typst_watch_paths(args: &ConfigArgs, assets_dir: &Path) -> Vec<(PathBuf, RecursiveMode)> {
+    let mut config_dirs: Vec<PathBuf> = server_config_files(args)
+        .iter()
+        .map(|file| parent_dir(file))
+        .collect();
+    config_dirs.dedup();
+    let config_dirs = config_dirs
+        .into_iter()
+        .map(|dir| (dir, RecursiveMode::NonRecursive));
+    std::iter::once((assets_dir.to_path_buf(), RecursiveMode::Recursive))
+        .chain(config_dirs)
+        .filter(|(path, _)| path.exists())
+        .collect()
+}
+
+fn is_typst_change(event: &notify::Event, args: &ConfigArgs, assets_dir: &Path) -> bool {
+    if matches!(event.kind, EventKind::Access(_)) {
+        return false;
+    }
+    let assets_dir = assets_dir
+        .canonicalize()
+        .unwrap_or_else(|_| assets_dir.to_path_buf());
+    event.paths.iter().any(|path| {
+        path.starts_with(&assets_dir)
+            || server_config_files(args)
+                .iter()
+                .any(|file| path.file_name() == file.file_name())
+    })
+}
+
+/// Rebuild themes when templates, fonts or the server config change, until
+/// the server shuts down.
+pub async fn spawn_typst_watcher(args: ConfigArgs, state: ServerState) -> error::Result<()> {
+    tokio::spawn(async move {
+        let (tx, mut rx) = mpsc::channel(1);
+        // A full channel already has a reload pending.
+        let mut watcher = recommended_watcher(move |res| {
+            let _ = tx.try_send(res);
+        })
+        .context(WatchFileSnafu)?;
+
+        let mut assets_dir = PathBuf::from(&state.typst.load().config.assets_dir);
+        let mut watched = typst_watch_paths(&args, &assets_dir);
+        for (path, mode) in &watched {
+            watcher.watch(path, *mode).context(WatchFileSnafu)?;
+        }
+
+        while let Some(event) = tokio::select! {
+            _ = state.shutdown.cancelled() => None,
+            event = rx.recv() => event,
+        } {
+            match event {
+                Ok(event) if is_typst_change(&event, &args, &assets_dir) => {
+                    tracing::debug!("typst config event: {:?}", event);
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    tracing::error!("watch typst config error: {}", e);
+                    continue;
+                }
+            }
+            tokio::time::sleep(RELOAD_DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+
+            let reload_state = state.clone();
+            let reload_args = args.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let config: Config = server_config_figment(&reload_args)
+                    .extract()
+                    .context(FigmentParseSnafu)?;
+                reload_state.reload_typst(config.typst)
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => {
+                    tracing::info!("reloaded typst config");
+                    metrics().typst_config_reloaded(true);
+                }
+                Ok(Err(e)) => {
+                    tracing::error!("keep current typst config: {}", e);
+                    metrics().typst_config_reloaded(false);
+                    continue;
+                }
+                Err(e) => {
+                    tracing::error!("reload typst config panicked: {}", e);
+                    metrics().typst_config_reloaded(false);
+                    continue;
+                }
+            }
+
+            let new_assets_dir = PathBuf::from(&state.typst.load().config.assets_dir);
+            if new_assets_dir != assets_dir {
+                for (path, _) in &watched {
+                    let _ = watcher.unwatch(path);
+                }
+                assets_dir = new_assets_dir;
+                watched = typst_watch_paths(&args, &assets_dir);
+                for (path, mode) in &watched {
+                    watcher.watch(path, *mode).context(WatchFileSnafu)?;
+                }
+            }
+        }
+        tracing::info!("stopped watching typst config");
         Ok::<(), error::Error>(())
     });
     Ok(())
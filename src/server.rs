@@ -1,32 +1,61 @@
-use std::sync::Arc;
+use std::{
+    io::{Cursor, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use axum::{
-    Json, Router,
+    Extension, Json, Router,
     body::Body,
-    extract::State,
+    extract::{Path, State},
     http::{
-        HeaderMap, HeaderValue,
-        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, HeaderName, HeaderValue,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE, ETAG},
     },
     middleware,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::{get, post},
+    serve::ListenerExt,
 };
+use base64::{Engine, prelude::BASE64_STANDARD};
+use color_eyre::eyre::eyre;
 use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, task::JoinSet};
+use tokio_util::sync::CancellationToken;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tracing::info;
+use zip::{ZipWriter, write::SimpleFileOptions};
 
 use crate::{
-    auth,
+    archive::{self, Archive},
+    auth::{self, AuthInfo, Authenticator},
+    authz::Authorizer,
+    batch,
+    cache::{CacheStatus, CachedReport, RenderCache, cache_key},
     client_config::ClientConfig,
-    config::{ServerConfig, TypstConfig},
-    error::{BindSnafu, Result, ServeSnafu},
-    extractor::ValidatedJson,
-    typst_lib::generate_pdf,
+    config::{ListenerConfig, RouteGroup, ServerConfig, TypstConfig},
+    error::{
+        BindSnafu, Error, FileIoSnafu, InvalidInputSnafu, JsonSerializeSnafu, Result, ServeSnafu,
+        ZipSnafu,
+    },
+    extractor::{ValidatedJson, ValidatedQuery},
+    health::{self, SmokeCheck},
+    jobs::{self, JobStore},
+    metrics::{self, metrics},
+    rate_limit::{self, RateLimiter},
+    render_pool::RenderPool,
+    shutdown::{self, InFlight},
+    tls::{self, TlsListener},
+    typst_lib::{
+        Entry, Output, OutputFormat, OutputOptions, PageSelection, RenderedPage, Renderer,
+        ReportSource, generate,
+    },
+    validate,
 };
 
 pub struct Server {
@@ -34,54 +63,440 @@ pub struct Server {
     pub client_config: Arc<ArcSwap<ClientConfig>>,
 }
 
+/// A typst config and the renderer built from it, swapped as one.
+pub struct Typst {
+    pub config: Arc<TypstConfig>,
+    pub renderer: Arc<Renderer>,
+}
+
 #[derive(Clone)]
 pub struct ServerState {
     pub client_config: Arc<ArcSwap<ClientConfig>>,
-    pub typst_config: Arc<TypstConfig>,
+    /// Why the last client config reload failed, the previous config still
+    /// being served; unset once a reload succeeds.
+    pub client_config_error: Arc<ArcSwapOption<String>>,
+    pub typst: Arc<ArcSwap<Typst>>,
+    pub render_pool: Arc<RenderPool>,
+    pub authenticator: Arc<Authenticator>,
+    pub authorizer: Arc<Authorizer>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub render_cache: Option<Arc<RenderCache>>,
+    pub jobs: Option<Arc<JobStore>>,
+    pub archive: Option<Arc<Archive>>,
+    /// Cancelled once the server starts shutting down, failing readiness
+    /// while requests are still served; also cancelled with `shutdown`.
+    pub draining: CancellationToken,
+    /// Cancelled once the listeners stop accepting connections.
+    pub shutdown: CancellationToken,
+    pub in_flight: Arc<InFlight>,
+    pub smoke_check: Arc<SmokeCheck>,
+}
+
+impl ServerState {
+    pub fn new(
+        config: &ServerConfig,
+        client_config: Arc<ArcSwap<ClientConfig>>,
+        typst_config: TypstConfig,
+    ) -> Result<Self> {
+        let authenticator = Authenticator::new(&config.auth)?;
+        let renderer = Renderer::new(&typst_config);
+        let render_pool = RenderPool::new(&typst_config.render);
+        let render_cache = typst_config.render.cache.as_ref().map(RenderCache::new);
+        let shutdown = CancellationToken::new();
+        Ok(Self {
+            client_config,
+            client_config_error: Arc::new(ArcSwapOption::empty()),
+            typst: Arc::new(ArcSwap::from_pointee(Typst {
+                config: Arc::new(typst_config),
+                renderer: Arc::new(renderer),
+            })),
+            render_pool: Arc::new(render_pool),
+            authenticator: Arc::new(authenticator),
+            authorizer: Arc::new(Authorizer::new(&config.authorization)),
+            rate_limiter: config
+                .rate_limit
+                .as_ref()
+                .map(|config| Arc::new(RateLimiter::new(config))),
+            render_cache: render_cache.map(Arc::new),
+            jobs: match &config.jobs {
+                Some(jobs) => Some(Arc::new(JobStore::new(jobs)?)),
+                None => None,
+            },
+            archive: match &config.archive {
+                Some(archive) => Some(Arc::new(Archive::new(archive)?)),
+                None => None,
+            },
+            draining: shutdown.child_token(),
+            shutdown,
+            in_flight: Arc::new(InFlight::default()),
+            smoke_check: Arc::new(SmokeCheck::default()),
+        })
+    }
+
+    /// Validate `typst_config` and swap it in with a rebuilt renderer; the
+    /// current config stays when the new one is invalid.
+    ///
+    /// Render pool and cache settings only take effect on restart.
+    pub fn reload_typst(&self, typst_config: TypstConfig) -> Result<()> {
+        validate::validate(&typst_config)?;
+        let renderer = self.typst.load().renderer.rebuild(&typst_config);
+        self.typst.store(Arc::new(Typst {
+            config: Arc::new(typst_config),
+            renderer: Arc::new(renderer),
+        }));
+        Ok(())
+    }
 }
 
+/// Whether a report came from the render cache, `hit` or `miss`.
+const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
 #[derive(Debug, Deserialize)]
 pub struct ReportRequest {
     pub name: String,
-    pub content: String,
+    /// Typst source compiled as `main.typ`; mutually exclusive with `template`.
+    pub content: Option<String>,
+    /// Theme template compiled as the entry file instead of `content`.
+    pub template: Option<String>,
+    /// Exposed to the document as `sys.inputs` and `data.json`.
+    pub data: Option<serde_json::Value>,
     pub theme: Option<String>,
+    #[serde(flatten)]
+    pub output: OutputOptions,
+    /// How png/svg pages are packed; a single page is sent as is when unset.
+    pub bundle: Option<PageBundle>,
 }
 
-#[tracing::instrument(name = "report", skip(payload))]
-pub async fn report(
-    State(ServerState { typst_config, .. }): State<ServerState>,
-    ValidatedJson(payload): ValidatedJson<ReportRequest>,
-) -> Result<impl IntoResponse> {
-    let state = typst_config.clone();
+impl ReportRequest {
+    /// Check that exactly one entry is given and the output options are
+    /// valid, defaulting the theme.
+    pub fn into_render(self) -> Result<RenderRequest> {
+        let entry = match (self.content, self.template) {
+            (Some(content), None) => Entry::Content(content),
+            (None, Some(template)) => Entry::Template(template),
+            _ => {
+                return InvalidInputSnafu {
+                    reason: "Exactly one of content and template is required",
+                }
+                .fail();
+            }
+        };
+        self.output.validate()?;
+        Ok(RenderRequest {
+            name: self.name,
+            source: ReportSource {
+                entry,
+                data: self.data,
+            },
+            theme: self.theme.unwrap_or(validate::DEFAULT_THEME.to_string()),
+            options: self.output,
+            bundle: self.bundle,
+        })
+    }
+}
+
+/// A report to render, shared by the synchronous and job endpoints.
+#[derive(Debug, Clone)]
+pub struct RenderRequest {
+    pub name: String,
+    pub source: ReportSource,
+    pub theme: String,
+    pub options: OutputOptions,
+    pub bundle: Option<PageBundle>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PageBundle {
+    Zip,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PageContent {
+    pub page: usize,
+    pub content_type: &'static str,
+    /// Base64 encoded page.
+    pub data: String,
+}
+
+/// `name` percent-encoded, safe as a `filename*` and as a zip entry name.
+pub(crate) fn encoded_filename(name: &str) -> String {
+    utf8_percent_encode(name, NON_ALPHANUMERIC).to_string()
+}
+
+pub(crate) fn attachment_headers(name: &str, content_type: &str, ext: &str) -> HeaderMap {
     let mut resp_header = HeaderMap::new();
-    resp_header.insert(CONTENT_TYPE, HeaderValue::from_static("application/pdf"));
-    let encoded_filename = utf8_percent_encode(&payload.name, NON_ALPHANUMERIC).to_string();
+    resp_header.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+    let encoded_filename = encoded_filename(name);
     let fallback_filename = "export";
     let disposition = format!(
         "attachment; filename=\"{fallback}{ext}\"; filename*=UTF-8''{encoded}{ext}",
         fallback = fallback_filename,
-        ext = ".pdf",
+        ext = ext,
         encoded = encoded_filename
     );
     resp_header.insert(
         CONTENT_DISPOSITION,
         HeaderValue::from_str(&disposition).unwrap(),
     );
-    let content = payload.content;
-    let pdf: Vec<u8> = generate_pdf(
-        content,
-        state.as_ref(),
-        payload.theme.unwrap_or("default".to_string()).as_str(),
-    )?;
-    let body = Body::from(pdf);
-    Ok((resp_header, body).into_response())
+    resp_header
+}
+
+fn zip_pages(pages: Vec<RenderedPage>, format: OutputFormat) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for page in pages {
+        zip.start_file(
+            format!("page-{}.{}", page.number, format.extension()),
+            SimpleFileOptions::default(),
+        )
+        .context(ZipSnafu)?;
+        zip.write_all(&page.data).context(FileIoSnafu)?;
+    }
+    Ok(zip.finish().context(ZipSnafu)?.into_inner())
+}
+
+/// Key of a report in the render cache, unset for an unknown theme.
+fn report_cache_key(
+    renderer: &Renderer,
+    theme: &str,
+    source: &ReportSource,
+    options: &OutputOptions,
+    bundle: Option<PageBundle>,
+) -> Option<String> {
+    let fingerprint = renderer.theme(theme).ok()?.fingerprint();
+    let (kind, entry) = match &source.entry {
+        Entry::Content(content) => ("content", content.as_str()),
+        Entry::Template(template) => ("template", template.as_str()),
+    };
+    let data = source
+        .data
+        .as_ref()
+        .map(|data| data.to_string())
+        .unwrap_or_default();
+    let output = format!("{options:?} {bundle:?}");
+    Some(cache_key([
+        env!("CARGO_PKG_VERSION").as_bytes(),
+        theme.as_bytes(),
+        fingerprint.as_bytes(),
+        kind.as_bytes(),
+        entry.as_bytes(),
+        data.as_bytes(),
+        output.as_bytes(),
+    ]))
+}
+
+/// Render `source` on the pool and pack the output as the response body;
+/// `on_start` runs once a worker picks the render up.
+pub(crate) async fn render_packed(
+    render_pool: Arc<RenderPool>,
+    renderer: Arc<Renderer>,
+    source: ReportSource,
+    theme: String,
+    options: OutputOptions,
+    bundle: Option<PageBundle>,
+    on_start: impl FnOnce() + Send + 'static,
+) -> Result<CachedReport> {
+    let format = options.format;
+    let started = Instant::now();
+    // Themes come from the client, so only known ones become a metrics label.
+    let label = match renderer.theme(&theme) {
+        Ok(_) => theme.clone(),
+        Err(_) => "unknown".to_string(),
+    };
+    let output = render_pool
+        .run(move || {
+            on_start();
+            generate(source, renderer.as_ref(), theme.as_str(), &options)
+        })
+        .await
+        .inspect_err(|e| metrics().render_failed(&label, e.kind()))?;
+    let size = match &output {
+        Output::Pdf(pdf) => pdf.len(),
+        Output::Pages(pages) => pages.iter().map(|page| page.data.len()).sum(),
+    };
+    metrics().observe_render(&label, format.extension(), started, size);
+    let (content_type, ext, body) = match (output, bundle) {
+        (Output::Pdf(pdf), _) => (format.content_type(), Some(".pdf".to_string()), pdf),
+        (Output::Pages(mut pages), None) if pages.len() == 1 => {
+            let ext = format!(".{}", format.extension());
+            (format.content_type(), Some(ext), pages.remove(0).data)
+        }
+        (Output::Pages(pages), Some(PageBundle::Json)) => {
+            let pages: Vec<PageContent> = pages
+                .into_iter()
+                .map(|page| PageContent {
+                    page: page.number,
+                    content_type: format.content_type(),
+                    data: BASE64_STANDARD.encode(page.data),
+                })
+                .collect();
+            let json = serde_json::to_vec(&pages).context(JsonSerializeSnafu)?;
+            ("application/json", None, json)
+        }
+        (Output::Pages(pages), _) => (
+            "application/zip",
+            Some(".zip".to_string()),
+            zip_pages(pages, format)?,
+        ),
+    };
+    Ok(CachedReport {
+        content_type: content_type.to_string(),
+        ext,
+        body: body.into(),
+    })
+}
+
+pub(crate) fn report_response(name: &str, report: &CachedReport) -> Response {
+    let headers = match &report.ext {
+        Some(ext) => attachment_headers(name, &report.content_type, ext),
+        None => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_str(&report.content_type).unwrap(),
+            );
+            headers
+        }
+    };
+    (headers, Body::from(report.body.clone())).into_response()
+}
+
+/// Render `request` for `user`, or serve it from the render cache when
+/// enabled, archiving the result; the cache key and status are returned for
+/// cached reports.
+pub(crate) async fn render_cached(
+    state: &ServerState,
+    user: &str,
+    request: RenderRequest,
+) -> Result<(Arc<CachedReport>, Option<(String, CacheStatus)>)> {
+    let RenderRequest {
+        name,
+        source,
+        theme,
+        options,
+        bundle,
+    } = request;
+    let _in_flight = state.in_flight.enter();
+    let label = theme.clone();
+    let renderer = Arc::clone(&state.typst.load().renderer);
+    let key = state
+        .render_cache
+        .as_ref()
+        .and_then(|_| report_cache_key(&renderer, &theme, &source, &options, bundle));
+    let render = || {
+        render_packed(
+            Arc::clone(&state.render_pool),
+            renderer,
+            source,
+            theme,
+            options,
+            bundle,
+            || (),
+        )
+    };
+    let (report, cached) = match (&state.render_cache, key) {
+        (Some(cache), Some(key)) => {
+            let (report, status) = cache.get_or_render(&key, render).await?;
+            (report, Some((key, status)))
+        }
+        _ => (Arc::new(render().await?), None),
+    };
+    archive::record(state, user, &label, &name, &report);
+    Ok((report, cached))
+}
+
+/// Render `request` for `user` as a download.
+async fn render_report(state: ServerState, user: &str, request: RenderRequest) -> Result<Response> {
+    let name = request.name.clone();
+    let (report, cached) = render_cached(&state, user, request).await?;
+    let mut response = report_response(&name, &report);
+    if let Some((key, status)) = cached {
+        let headers = response.headers_mut();
+        headers.insert(ETAG, HeaderValue::from_str(&format!("\"{key}\"")).unwrap());
+        headers.insert(X_CACHE, HeaderValue::from_static(status.as_str()));
+    }
+    Ok(response)
+}
+
+#[tracing::instrument(name = "report", skip(state, payload), fields(user = %auth_info.user_id))]
+pub async fn report(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    ValidatedJson(payload): ValidatedJson<ReportRequest>,
+) -> Result<impl IntoResponse> {
+    let request = payload.into_render()?;
+    state
+        .authorizer
+        .authorize_theme(&auth_info, &request.theme)?;
+    render_report(state, &auth_info.user_id, request).await
+}
+
+/// Output options of a server template render, passed in the query string.
+#[derive(Debug, Deserialize)]
+pub struct TemplateReportQuery {
+    /// Download name, defaults to the template name.
+    pub name: Option<String>,
+    #[serde(default)]
+    pub format: OutputFormat,
+    pub pages: Option<PageSelection>,
+    pub pixel_per_pt: Option<f32>,
+    pub bundle: Option<PageBundle>,
+}
+
+/// Render a template owned by the server with the JSON body as its data.
+#[tracing::instrument(
+    name = "report_template",
+    skip(state, auth_info, query, data),
+    fields(user = %auth_info.user_id)
+)]
+pub async fn report_template(
+    State(state): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+    Path((theme, template)): Path<(String, String)>,
+    ValidatedQuery(query): ValidatedQuery<TemplateReportQuery>,
+    ValidatedJson(data): ValidatedJson<serde_json::Value>,
+) -> Result<impl IntoResponse> {
+    state.authorizer.authorize_theme(&auth_info, &theme)?;
+    let name = query.name.unwrap_or_else(|| {
+        template
+            .strip_suffix(".typ")
+            .unwrap_or(&template)
+            .to_string()
+    });
+    let mut options = OutputOptions {
+        format: query.format,
+        pages: query.pages,
+        ..Default::default()
+    };
+    if let Some(pixel_per_pt) = query.pixel_per_pt {
+        options.pixel_per_pt = pixel_per_pt;
+    }
+    options.validate()?;
+    let request = RenderRequest {
+        name,
+        source: ReportSource {
+            entry: Entry::Template(template),
+            data: Some(data),
+        },
+        theme,
+        options,
+        bundle: query.bundle,
+    };
+    render_report(state, &auth_info.user_id, request).await
 }
 
 pub async fn client_config_handler(
-    State(ServerState { client_config, .. }): State<ServerState>,
-) -> impl IntoResponse {
+    State(ServerState {
+        client_config,
+        authorizer,
+        ..
+    }): State<ServerState>,
+    Extension(auth_info): Extension<AuthInfo>,
+) -> Result<impl IntoResponse> {
+    authorizer.authorize_client_config(&auth_info)?;
     let arc_cfg: Arc<ClientConfig> = client_config.load().clone();
-    Json(arc_cfg)
+    Ok(Json(arc_cfg))
 }
 
 impl Server {
@@ -100,40 +515,245 @@ impl Server {
         router
     }
 
+    /// Routes that render, limited per client when rate limiting is on.
+    fn report_router(state: &ServerState) -> Router<ServerState> {
+        let mut router = Router::new()
+            .route("/report", post(report))
+            .route("/report/batch", post(batch::report_batch))
+            .route("/report/{theme}/{template}", post(report_template));
+        if state.jobs.is_some() {
+            router = router.route("/reports/jobs", post(jobs::create_job));
+        }
+        match &state.rate_limiter {
+            Some(limiter) => router.route_layer(middleware::from_fn_with_state(
+                Arc::clone(limiter),
+                rate_limit::rate_limit,
+            )),
+            None => router,
+        }
+    }
+
+    pub fn router(&self, state: ServerState) -> Router {
+        self.router_with(state, &RouteGroup::all())
+    }
+
+    /// Router serving only the `routes` groups.
+    pub fn router_with(&self, state: ServerState, routes: &[RouteGroup]) -> Router {
+        let mut router = Router::new();
+        if routes.contains(&RouteGroup::Static) {
+            router = self.public_dir_dist(router);
+        }
+        router = router.layer(TraceLayer::new_for_http());
+        if routes.contains(&RouteGroup::Health) {
+            router = router
+                .route("/", get(|| async { "ok" }))
+                .route(
+                    "/version",
+                    get(|| async {
+                        let version = env!("CARGO_PKG_VERSION");
+                        format!("version: {}", version)
+                    }),
+                )
+                .route("/health", get(|| async { "ok" }))
+                .route("/livez", get(health::livez))
+                .route("/readyz", get(health::readyz));
+        }
+        if routes.contains(&RouteGroup::Metrics) {
+            router = router.route("/metrics", get(metrics::metrics_handler));
+        }
+        if routes.contains(&RouteGroup::Report) || routes.contains(&RouteGroup::ClientConfig) {
+            let mut api = Router::new().with_state(state.clone());
+            if routes.contains(&RouteGroup::Report) {
+                api = api.merge(Self::report_router(&state));
+                if state.jobs.is_some() {
+                    api = api
+                        .route(
+                            "/reports/jobs/{id}",
+                            get(jobs::job_status).delete(jobs::cancel_job),
+                        )
+                        .route("/reports/jobs/{id}/file", get(jobs::job_file));
+                }
+                if state.archive.is_some() {
+                    api = api
+                        .route("/reports", get(archive::list_reports))
+                        .route("/reports/{id}", get(archive::report_info))
+                        .route("/reports/{id}/file", get(archive::report_file));
+                }
+            }
+            if routes.contains(&RouteGroup::ClientConfig) {
+                api = api.route("/client_config", get(client_config_handler));
+            }
+            router = router.nest(
+                "/api",
+                api.layer(middleware::from_fn_with_state(
+                    Arc::clone(&state.authenticator),
+                    auth::simple_token_auth,
+                ))
+                .layer(TraceLayer::new_for_http()) as Router<ServerState>,
+            );
+        }
+        router
+            .layer(middleware::from_fn(metrics::track_requests))
+            .with_state(state)
+    }
+
+    /// Configured listeners, or one on `host`:`port` serving everything.
+    fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.config.listeners.is_empty() {
+            return self.config.listeners.clone();
+        }
+        vec![ListenerConfig {
+            bind: format!("{}:{}", self.config.host, self.config.port),
+            routes: RouteGroup::all(),
+            tls: true,
+        }]
+    }
+
     pub async fn run(&self, typst_config: TypstConfig) -> Result<()> {
-        let addr = format!("{}:{}", self.config.host, self.config.port);
-        let typst_config = Arc::new(typst_config);
-        let listener = TcpListener::bind(&addr).await.context(BindSnafu)?;
-        info!("Server is running on http://{}", &addr);
-        let state = ServerState {
-            client_config: Arc::clone(&self.client_config),
-            typst_config: typst_config.clone(),
+        let state = ServerState::new(&self.config, Arc::clone(&self.client_config), typst_config)?;
+        self.serve(state).await
+    }
+
+    pub async fn serve(&self, state: ServerState) -> Result<()> {
+        let shutdown = state.shutdown.clone();
+        let in_flight = Arc::clone(&state.in_flight);
+        let tls = match &self.config.tls {
+            Some(tls_config) => {
+                let tls = Arc::new(ArcSwap::from_pointee(tls::load(tls_config)?));
+                tls::spawn_cert_watcher(tls_config.clone(), Arc::clone(&tls), shutdown.clone())
+                    .await?;
+                Some(tls)
+            }
+            None => None,
         };
-        // let router: Router<ServerState> = Router::new();
-        let router = self
-            .public_dir_dist(Router::new())
-            .layer(TraceLayer::new_for_http())
-            .route("/", get(|| async { "ok" }))
-            .route(
-                "/version",
-                get(|| async {
-                    let version = env!("CARGO_PKG_VERSION");
-                    format!("version: {}", version)
-                }),
-            )
-            .route("/health", get(|| async { "ok" }))
-            .nest(
-                "/api",
-                Router::new()
-                    .with_state(state.clone())
-                    .route("/report", post(report))
-                    .route("/client_config", get(client_config_handler))
-                    .layer(middleware::from_fn(auth::simple_token_auth))
-                    .layer(TraceLayer::new_for_http()) as Router<ServerState>,
-            )
-            .with_state(state);
-        let app = router.into_make_service();
-        axum::serve(listener, app).await.context(ServeSnafu)?;
+        let mut servers = JoinSet::new();
+        let mut sockets = vec![];
+        for listener in self.listeners() {
+            let app = self.router_with(state.clone(), &listener.routes);
+            let tls = tls.as_ref().filter(|_| listener.tls);
+            if let Some(socket) =
+                spawn_listener(&mut servers, &listener, app, tls, &shutdown).await?
+            {
+                sockets.push(socket);
+            }
+        }
+
+        tokio::select! {
+            Some(result) = servers.join_next() => {
+                return result
+                    .map_err(|e| eyre!("server task failed: {e}"))?
+                    .context(ServeSnafu);
+            }
+            _ = shutdown::signal() => {}
+        }
+
+        // Fail readiness first, so load balancers stop sending requests while
+        // the listeners still serve them.
+        state.draining.cancel();
+        let draining = in_flight.count();
+        let drain_timeout = Duration::from_secs(self.config.drain_timeout_secs);
+        info!(
+            "shutting down, waiting up to {}s for {} in-flight reports",
+            drain_timeout.as_secs(),
+            draining
+        );
+        let started = Instant::now();
+        let delay = Duration::from_secs(self.config.shutdown_delay_secs).min(drain_timeout);
+        tokio::time::sleep(delay).await;
+        // Stop accepting connections and the config watchers.
+        shutdown.cancel();
+        let drained = tokio::time::timeout(drain_timeout - delay, async {
+            while let Some(result) = servers.join_next().await {
+                result
+                    .map_err(|e| eyre!("server task failed: {e}"))?
+                    .context(ServeSnafu)?;
+            }
+            // Jobs and batches keep rendering after their response was sent.
+            in_flight.idle().await;
+            Ok::<(), Error>(())
+        })
+        .await;
+        for socket in &sockets {
+            let _ = std::fs::remove_file(socket);
+        }
+        match drained {
+            Ok(result) => {
+                result?;
+                info!(
+                    "shutdown complete in {:.1}s, drained {} in-flight reports",
+                    started.elapsed().as_secs_f64(),
+                    draining
+                );
+            }
+            Err(_) => {
+                servers.abort_all();
+                tracing::warn!(
+                    "shutdown drain timed out after {}s, abandoned {} of {} in-flight reports",
+                    drain_timeout.as_secs(),
+                    in_flight.count(),
+                    draining
+                );
+            }
+        }
         Ok(())
     }
 }
+
+type Servers = JoinSet<std::io::Result<()>>;
+
+/// Bind `listener` and serve `app` on it until shutdown, returning the path
+/// of a Unix socket to clean up afterwards.
+async fn spawn_listener(
+    servers: &mut Servers,
+    listener: &ListenerConfig,
+    app: Router,
+    tls: Option<&Arc<ArcSwap<rustls::ServerConfig>>>,
+    shutdown: &CancellationToken,
+) -> Result<Option<PathBuf>> {
+    let graceful = shutdown.clone().cancelled_owned();
+    if let Some(path) = listener.bind.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            // A socket left behind by a killed server would fail the bind.
+            if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                std::fs::remove_file(path).context(BindSnafu)?;
+            }
+            let unix = tokio::net::UnixListener::bind(path).context(BindSnafu)?;
+            info!("Server is running on unix:{}", path);
+            servers.spawn(
+                axum::serve(unix, app.into_make_service())
+                    .with_graceful_shutdown(graceful)
+                    .into_future(),
+            );
+            return Ok(Some(PathBuf::from(path)));
+        }
+        #[cfg(not(unix))]
+        return Err(eyre!("unix socket {path} is not supported on this platform").into());
+    }
+
+    let tcp = TcpListener::bind(&listener.bind).await.context(BindSnafu)?;
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        Some(tls) => {
+            let tls_listener =
+                TlsListener::new(tcp, Arc::clone(tls), shutdown.clone()).context(BindSnafu)?;
+            info!("Server is running on https://{}", listener.bind);
+            // Tapping keeps `ConnectInfo<SocketAddr>` available to handlers.
+            servers.spawn(
+                axum::serve(tls_listener.tap_io(|_| ()), app)
+                    .with_graceful_shutdown(graceful)
+                    .into_future(),
+            );
+        }
+        None => {
+            info!("Server is running on http://{}", listener.bind);
+            servers.spawn(
+                axum::serve(tcp, app)
+                    .with_graceful_shutdown(graceful)
+                    .into_future(),
+            );
+        }
+    }
+    Ok(None)
+}
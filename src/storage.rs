@@ -0,0 +1,235 @@
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+};
+
+use bytes::Bytes;
+use snafu::ResultExt;
+
+use crate::{
+    config::{S3Config, TypstConfig},
+    error::{FileIoSnafu, Result},
+    s3::{S3Client, S3Settings},
+};
+
+/// Objects addressed by `/` separated keys, in a local directory or under a
+/// prefix of an S3-compatible bucket.
+pub enum Storage {
+    Local(PathBuf),
+    S3(Box<S3Client>),
+}
+
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Storage::Local(dir) => write!(f, "{}", dir.display()),
+            Storage::S3(client) => f.write_str(&client.location()),
+        }
+    }
+}
+
+/// Whether `key` stays inside the storage root when used as a relative path.
+fn is_safe_key(key: &str) -> bool {
+    !key.is_empty()
+        && Path::new(key)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+impl Storage {
+    /// The bucket prefix of `s3` when set, completed from the environment,
+    /// otherwise the directory `dir`, created if missing.
+    pub fn new(dir: &str, s3: Option<&S3Config>) -> Result<Self> {
+        match s3 {
+            Some(config) => Ok(Storage::S3(Box::new(S3Client::new(S3Settings::from_env(
+                config,
+            )?)?))),
+            None => {
+                std::fs::create_dir_all(dir).context(FileIoSnafu)?;
+                Ok(Storage::Local(PathBuf::from(dir)))
+            }
+        }
+    }
+
+    pub async fn put(&self, key: &str, body: Bytes) -> Result<()> {
+        match self {
+            Storage::Local(dir) => {
+                let path = dir.join(key);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent)
+                        .await
+                        .context(FileIoSnafu)?;
+                }
+                tokio::fs::write(path, body).await.context(FileIoSnafu)
+            }
+            Storage::S3(client) => client.put(key, body).await,
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Bytes> {
+        match self {
+            Storage::Local(dir) => Ok(tokio::fs::read(dir.join(key))
+                .await
+                .context(FileIoSnafu)?
+                .into()),
+            Storage::S3(client) => client.get(key).await,
+        }
+    }
+
+    /// Remove `key`, succeeding if it does not exist.
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        match self {
+            Storage::Local(dir) => match tokio::fs::remove_file(dir.join(key)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).context(FileIoSnafu),
+                _ => Ok(()),
+            },
+            Storage::S3(client) => client.delete(key).await,
+        }
+    }
+
+    /// Keys of every object, including those in nested directories.
+    pub async fn list(&self) -> Result<Vec<String>> {
+        let dir = match self {
+            Storage::Local(dir) => dir,
+            Storage::S3(client) => return client.list().await,
+        };
+        let mut keys = vec![];
+        let mut pending = vec![(dir.clone(), String::new())];
+        while let Some((dir, prefix)) = pending.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await.context(FileIoSnafu)?;
+            while let Some(entry) = entries.next_entry().await.context(FileIoSnafu)? {
+                let key = format!("{prefix}{}", entry.file_name().to_string_lossy());
+                if entry.file_type().await.context(FileIoSnafu)?.is_dir() {
+                    pending.push((entry.path(), format!("{key}/")));
+                } else {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Copy every object of `source` to `target`, skipping keys that would
+/// escape it, and return how many were copied.
+async fn copy_all(source: &Storage, target: &Storage) -> Result<usize> {
+    let mut count = 0;
+    for key in source.list().await? {
+        if key.ends_with('/') {
+            continue;
+        }
+        if !is_safe_key(&key) {
+            tracing::warn!("skipping {} outside of {}", key, target);
+            continue;
+        }
+        target.put(&key, source.get(&key).await?).await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Download the fonts and templates under `typst.assets_s3` into
+/// `assets_dir`, overwriting local copies; nothing to do when unset.
+pub async fn sync_assets(config: &TypstConfig) -> Result<()> {
+    let Some(s3) = &config.assets_s3 else {
+        return Ok(());
+    };
+    let source = Storage::new(&config.assets_dir, Some(s3))?;
+    let target = Storage::new(&config.assets_dir, None)?;
+    let count = copy_all(&source, &target).await?;
+    tracing::info!(
+        "downloaded {} assets from {} into {}",
+        count,
+        source,
+        target
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        process,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use super::*;
+    use crate::s3;
+
+    fn temp_dir() -> PathBuf {
+        static DIRS: AtomicU64 = AtomicU64::new(0);
+        std::env::temp_dir().join(format!(
+            "storage-test-{}-{}",
+            process::id(),
+            DIRS.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    async fn roundtrip(storage: &Storage) {
+        storage.put("a.json", Bytes::from("{}")).await.unwrap();
+        storage
+            .put("fonts/b.ttf", Bytes::from("font"))
+            .await
+            .unwrap();
+        assert_eq!(storage.list().await.unwrap(), ["a.json", "fonts/b.ttf"]);
+        assert_eq!(storage.get("fonts/b.ttf").await.unwrap(), "font");
+        storage.delete("a.json").await.unwrap();
+        storage.delete("a.json").await.unwrap();
+        assert_eq!(storage.list().await.unwrap(), ["fonts/b.ttf"]);
+        assert!(storage.get("a.json").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let dir = temp_dir();
+        roundtrip(&Storage::new(&dir.display().to_string(), None).unwrap()).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_s3_storage() {
+        let (endpoint, _) = s3::tests::stand_in().await;
+        let client = S3Client::new(s3::tests::settings(&endpoint, "prefix/")).unwrap();
+        roundtrip(&Storage::S3(Box::new(client))).await;
+    }
+
+    #[test]
+    fn test_is_safe_key() {
+        assert!(is_safe_key("fonts/a.ttf"));
+        assert!(!is_safe_key("../a.ttf"));
+        assert!(!is_safe_key("/etc/passwd"));
+        assert!(!is_safe_key("fonts/../../a"));
+        assert!(!is_safe_key(""));
+    }
+
+    #[tokio::test]
+    async fn test_copy_assets_from_bucket() {
+        let (endpoint, objects) = s3::tests::stand_in().await;
+        {
+            let mut objects = objects.lock().unwrap();
+            objects.insert("assets/fonts/a.ttf".to_string(), Bytes::from("font"));
+            objects.insert(
+                "assets/themes/default/main.typ".to_string(),
+                Bytes::from("= Hi"),
+            );
+            objects.insert("assets/../escape".to_string(), Bytes::from("no"));
+            objects.insert("other/b.ttf".to_string(), Bytes::from("other"));
+        }
+        let source = Storage::S3(Box::new(
+            S3Client::new(s3::tests::settings(&endpoint, "assets/")).unwrap(),
+        ));
+        let dir = temp_dir();
+        let target = Storage::new(&dir.display().to_string(), None).unwrap();
+        assert_eq!(copy_all(&source, &target).await.unwrap(), 2);
+        assert_eq!(
+            target.list().await.unwrap(),
+            ["fonts/a.ttf", "themes/default/main.typ"]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("themes/default/main.typ")).unwrap(),
+            "= Hi"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
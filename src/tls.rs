@@ -0,0 +1,404 @@
+use std::{io, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use axum::serve::Listener;
+use notify::{EventKind, RecursiveMode, Watcher, recommended_watcher};
+use rustls::{
+    RootCertStore, ServerConfig as RustlsConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use snafu::ResultExt;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::TlsConfig,
+    error::{Result, TlsClientCaSnafu, TlsPemSnafu, TlsSnafu, WatchFileSnafu},
+    run::parent_dir,
+};
+
+/// Connections whose handshake takes longer are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait for the rest of a certificate rotation before reloading.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Handshaken connections waiting for the server to pick them up.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Build the rustls config of `config`, offering HTTP/2 and HTTP/1.1 via ALPN.
+pub fn load(config: &TlsConfig) -> Result<RustlsConfig> {
+    let certs = CertificateDer::pem_file_iter(&config.cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .context(TlsPemSnafu {
+            path: &config.cert_file,
+        })?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_file).context(TlsPemSnafu {
+        path: &config.key_file,
+    })?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = RustlsConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .context(TlsSnafu)?;
+    let builder = match &config.client_ca_file {
+        Some(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in
+                CertificateDer::pem_file_iter(ca_file).context(TlsPemSnafu { path: ca_file })?
+            {
+                let cert = cert.context(TlsPemSnafu { path: ca_file })?;
+                roots.add(cert).context(TlsSnafu)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context(TlsClientCaSnafu)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut tls = builder.with_single_cert(certs, key).context(TlsSnafu)?;
+    tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(tls)
+}
+
+/// TLS connections of a TCP listener, handshaken off the accept loop with the
+/// current certificate.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(
+        listener: TcpListener,
+        tls: Arc<ArcSwap<RustlsConfig>>,
+        shutdown: CancellationToken,
+    ) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, connections) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::error!("accept error: {}", e);
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    },
+                };
+                let acceptor = TlsAcceptor::from(tls.load_full());
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("tls handshake with {} failed: {}", addr, e),
+                        Err(_) => tracing::debug!("tls handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accept loop only stops on shutdown.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Swap in the certificate currently on disk, keeping the old one if it does
+/// not load.
+pub fn reload(config: &TlsConfig, tls: &ArcSwap<RustlsConfig>) -> Result<()> {
+    tls.store(Arc::new(load(config)?));
+    Ok(())
+}
+
+/// Reload the certificate when anything in the directories of its files
+/// changes, until `shutdown` is cancelled.
+pub async fn spawn_cert_watcher(
+    config: TlsConfig,
+    tls: Arc<ArcSwap<RustlsConfig>>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel(1);
+    // A full channel already has a reload pending.
+    let mut watcher = recommended_watcher(move |res| {
+        let _ = tx.try_send(res);
+    })
+    .context(WatchFileSnafu)?;
+    let mut dirs: Vec<PathBuf> = [Some(&config.cert_file), Some(&config.key_file)]
+        .into_iter()
+        .chain([config.client_ca_file.as_ref()])
+        .flatten()
+        .map(|file| parent_dir(&PathBuf::from(file)))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in &dirs {
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .context(WatchFileSnafu)?;
+    }
+
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        while let Some(event) = tokio::select! {
+            _ = shutdown.cancelled() => None,
+            event = rx.recv() => event,
+        } {
+            match event {
+                // Kubernetes rotates a mounted secret by swapping its `..data`
+                // symlink, never touching the file names, so any change in
+                // the watched directories reloads.
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                    tracing::debug!("tls event: {:?}", event);
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    tracing::error!("watch tls files error: {}", e);
+                    continue;
+                }
+            }
+            // Cert and key are usually replaced one after the other.
+            tokio::time::sleep(RELOAD_DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+            match reload(&config, &tls) {
+                Ok(()) => tracing::info!("reloaded tls certificate"),
+                Err(e) => tracing::error!("keep current tls certificate: {}", e),
+            }
+        }
+        tracing::info!("stopped watching tls certificate");
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{Router, routing::get, serve::ListenerExt};
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair,
+        generate_simple_self_signed,
+    };
+
+    use super::*;
+
+    struct Files {
+        dir: PathBuf,
+    }
+
+    impl Files {
+        fn new() -> Self {
+            static DIRS: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "tls-test-{}-{}",
+                std::process::id(),
+                DIRS.fetch_add(1, Ordering::SeqCst)
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self { dir }
+        }
+
+        fn write(&self, name: &str, content: &str) -> String {
+            let path = self.dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            path.display().to_string()
+        }
+
+        /// A self-signed `localhost` certificate, returned as PEM.
+        fn server_cert(&self) -> (TlsConfig, String) {
+            let CertifiedKey { cert, key_pair } =
+                generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let config = TlsConfig {
+                cert_file: self.write("cert.pem", &cert.pem()),
+                key_file: self.write("key.pem", &key_pair.serialize_pem()),
+                client_ca_file: None,
+            };
+            (config, cert.pem())
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    async fn serve(tls: Arc<ArcSwap<RustlsConfig>>) -> (SocketAddr, CancellationToken) {
+        let shutdown = CancellationToken::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TlsListener::new(listener, tls, shutdown.clone()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(async move {
+            axum::serve(
+                listener.tap_io(|_| ()),
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        (addr, shutdown)
+    }
+
+    fn client(ca_pem: &str, identity: Option<reqwest::Identity>) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(ca_pem.as_bytes()).unwrap())
+            .resolve("localhost", "127.0.0.1:0".parse().unwrap());
+        if let Some(identity) = identity {
+            builder = builder.identity(identity);
+        }
+        builder.build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tls_http2_and_reload() {
+        let files = Files::new();
+        let (config, ca_pem) = files.server_cert();
+        let tls = Arc::new(ArcSwap::from_pointee(load(&config).unwrap()));
+        let (addr, shutdown) = serve(Arc::clone(&tls)).await;
+        let url = format!("https://localhost:{}/", addr.port());
+
+        let response = client(&ca_pem, None).get(&url).send().await.unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        // A rotated certificate is served to new connections.
+        let (_, new_ca_pem) = files.server_cert();
+        reload(&config, &tls).unwrap();
+        let response = client(&new_ca_pem, None).get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+        assert!(client(&ca_pem, None).get(&url).send().await.is_err());
+
+        // A broken rotation keeps the current certificate.
+        files.write("key.pem", "not a key");
+        assert!(reload(&config, &tls).is_err());
+        let response = client(&new_ca_pem, None).get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+        shutdown.cancel();
+    }
+
+    /// Point the `..data` symlink of a Kubernetes style secret mount at a new
+    /// version holding a fresh certificate, returned as PEM.
+    #[cfg(unix)]
+    fn rotate_secret(dir: &std::path::Path, version: &str) -> String {
+        use std::os::unix::fs::symlink;
+
+        let CertifiedKey { cert, key_pair } =
+            generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let data = dir.join(version);
+        std::fs::create_dir(&data).unwrap();
+        std::fs::write(data.join("cert.pem"), cert.pem()).unwrap();
+        std::fs::write(data.join("key.pem"), key_pair.serialize_pem()).unwrap();
+        symlink(version, dir.join("..data_tmp")).unwrap();
+        std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+        cert.pem()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cert_watcher_follows_secret_rotation() {
+        use std::os::unix::fs::symlink;
+
+        let files = Files::new();
+        let ca_pem = rotate_secret(&files.dir, "..v1");
+        for name in ["cert.pem", "key.pem"] {
+            symlink(format!("..data/{name}"), files.dir.join(name)).unwrap();
+        }
+        let config = TlsConfig {
+            cert_file: files.dir.join("cert.pem").display().to_string(),
+            key_file: files.dir.join("key.pem").display().to_string(),
+            client_ca_file: None,
+        };
+        let tls = Arc::new(ArcSwap::from_pointee(load(&config).unwrap()));
+        let (addr, shutdown) = serve(Arc::clone(&tls)).await;
+        spawn_cert_watcher(config, Arc::clone(&tls), shutdown.clone())
+            .await
+            .unwrap();
+        let url = format!("https://localhost:{}/", addr.port());
+        assert!(client(&ca_pem, None).get(&url).send().await.is_ok());
+
+        let new_ca_pem = rotate_secret(&files.dir, "..v2");
+        let mut reloaded = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if client(&new_ca_pem, None).get(&url).send().await.is_ok() {
+                reloaded = true;
+                break;
+            }
+        }
+        assert!(reloaded);
+        shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_tls_client_ca() {
+        let files = Files::new();
+        let (mut config, ca_pem) = files.server_cert();
+
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        config.client_ca_file = Some(files.write("ca.pem", &ca.pem()));
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = CertificateParams::new(vec!["client".to_string()])
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+        let identity = reqwest::Identity::from_pem(
+            format!("{}{}", client_cert.pem(), client_key.serialize_pem()).as_bytes(),
+        )
+        .unwrap();
+
+        let tls = Arc::new(ArcSwap::from_pointee(load(&config).unwrap()));
+        let (addr, shutdown) = serve(tls).await;
+        let url = format!("https://localhost:{}/", addr.port());
+
+        assert!(client(&ca_pem, None).get(&url).send().await.is_err());
+        let response = client(&ca_pem, Some(identity))
+            .get(&url)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+        shutdown.cancel();
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let config = TlsConfig {
+            cert_file: "/nonexistent/cert.pem".to_string(),
+            key_file: "/nonexistent/key.pem".to_string(),
+            client_ca_file: None,
+        };
+        assert!(load(&config).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use serde_json::json;
+use snafu::ResultExt;
+
+use crate::{
+    auth::{AuthInfo, sha256_hex},
+    config::TokenReviewConfig,
+    error::{Error as ApiError, FileIoSnafu, Result, TokenReviewSnafu},
+};
+
+/// Cached reviews are pruned once the cache grows past this size.
+const CACHE_PRUNE_SIZE: usize = 1024;
+
+/// Authenticates bearer tokens with the Kubernetes `TokenReview` API.
+pub struct TokenReviewer {
+    client: reqwest::Client,
+    url: String,
+    /// File of the token this server presents to the API server, read for
+    /// every review since the kubelet rotates projected tokens in place.
+    token_file: Option<PathBuf>,
+    audiences: Vec<String>,
+    ttl: Duration,
+    /// Positive reviews by token hash.
+    cache: Mutex<HashMap<String, (AuthInfo, Instant)>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenReview {
+    #[serde(default)]
+    status: TokenReviewStatus,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TokenReviewStatus {
+    #[serde(default)]
+    authenticated: bool,
+    #[serde(default)]
+    user: TokenReviewUser,
+    error: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TokenReviewUser {
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl TokenReviewer {
+    pub fn new(config: &TokenReviewConfig) -> Result<Self> {
+        let mut builder =
+            reqwest::Client::builder().timeout(Duration::from_secs(config.timeout_secs));
+        if let Some(ca_file) = &config.ca_file {
+            let pem = std::fs::read(ca_file).context(FileIoSnafu)?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem).context(TokenReviewSnafu)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        let token_file = config.token_file.as_ref().map(PathBuf::from);
+        if let Some(path) = &token_file {
+            std::fs::read_to_string(path).context(FileIoSnafu)?;
+        }
+        Ok(Self {
+            client: builder.build().context(TokenReviewSnafu)?,
+            url: format!(
+                "{}/apis/authentication.k8s.io/v1/tokenreviews",
+                config.api_server.trim_end_matches('/')
+            ),
+            token_file,
+            audiences: config.audiences.clone(),
+            ttl: Duration::from_secs(config.cache_ttl_secs),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn verify(&self, token: &str) -> Result<AuthInfo> {
+        let key = sha256_hex(token);
+        if let Some(auth_info) = self.cached(&key) {
+            return Ok(auth_info);
+        }
+
+        let mut spec = json!({ "token": token });
+        if !self.audiences.is_empty() {
+            spec["audiences"] = json!(self.audiences);
+        }
+        let body = json!({
+            "apiVersion": "authentication.k8s.io/v1",
+            "kind": "TokenReview",
+            "spec": spec,
+        });
+        let mut request = self.client.post(&self.url).json(&body);
+        if let Some(path) = &self.token_file {
+            let token = tokio::fs::read_to_string(path).await.context(FileIoSnafu)?;
+            request = request.bearer_auth(token.trim());
+        }
+        let review: TokenReview = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .context(TokenReviewSnafu)?
+            .json()
+            .await
+            .context(TokenReviewSnafu)?;
+
+        let status = review.status;
+        if !status.authenticated || status.user.username.is_empty() {
+            tracing::debug!("token review rejected token: {:?}", status.error);
+            return Err(ApiError::InvalidToken);
+        }
+        let auth_info = AuthInfo {
+            user_id: status.user.username,
+            groups: status.user.groups,
+        };
+        self.store(key, auth_info.clone());
+        Ok(auth_info)
+    }
+
+    fn cached(&self, key: &str) -> Option<AuthInfo> {
+        let cache = self.cache.lock().ok()?;
+        let (auth_info, expires) = cache.get(key)?;
+        (*expires > Instant::now()).then(|| auth_info.clone())
+    }
+
+    fn store(&self, key: String, auth_info: AuthInfo) {
+        let Ok(mut cache) = self.cache.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        if cache.len() >= CACHE_PRUNE_SIZE {
+            cache.retain(|_, (_, expires)| *expires > now);
+        }
+        cache.insert(key, (auth_info, now + self.ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{Json, Router, extract::State, http::HeaderMap, routing::post};
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A stand-in API server that accepts `good-token` only.
+    async fn mock_api_server(calls: Arc<AtomicUsize>) -> String {
+        async fn review(
+            State(calls): State<Arc<AtomicUsize>>,
+            headers: HeaderMap,
+            Json(body): Json<Value>,
+        ) -> Json<Value> {
+            calls.fetch_add(1, Ordering::SeqCst);
+            assert_eq!(headers.get("authorization").unwrap(), "Bearer reviewer");
+            assert_eq!(body["kind"], "TokenReview");
+            assert_eq!(body["spec"]["audiences"][0], "kube-eye");
+            if body["spec"]["token"] == "good-token" {
+                Json(json!({
+                    "status": {
+                        "authenticated": true,
+                        "user": {
+                            "username": "system:serviceaccount:kubesphere:admin",
+                            "groups": ["system:authenticated"]
+                        }
+                    }
+                }))
+            } else {
+                Json(json!({
+                    "status": { "authenticated": false, "error": "invalid bearer token" }
+                }))
+            }
+        }
+
+        let app = Router::new()
+            .route("/apis/authentication.k8s.io/v1/tokenreviews", post(review))
+            .with_state(calls);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    /// A reviewer token file, removed on drop.
+    struct TokenFile(PathBuf);
+
+    impl Drop for TokenFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn reviewer(api_server: String, cache_ttl_secs: u64) -> (TokenReviewer, TokenFile) {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let token_file = TokenFile(std::env::temp_dir().join(format!(
+            "token-review-{}-{}",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::SeqCst)
+        )));
+        std::fs::write(&token_file.0, "reviewer\n").unwrap();
+        let reviewer = TokenReviewer::new(&TokenReviewConfig {
+            api_server,
+            ca_file: None,
+            token_file: Some(token_file.0.display().to_string()),
+            audiences: vec!["kube-eye".to_string()],
+            cache_ttl_secs,
+            timeout_secs: 5,
+        })
+        .unwrap();
+        (reviewer, token_file)
+    }
+
+    #[tokio::test]
+    async fn test_token_review_authenticated_and_cached() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (reviewer, _token_file) = reviewer(mock_api_server(Arc::clone(&calls)).await, 60);
+
+        for _ in 0..3 {
+            let auth_info = reviewer.verify("good-token").await.unwrap();
+            assert_eq!(auth_info.user_id, "system:serviceaccount:kubesphere:admin");
+            assert_eq!(auth_info.groups, vec!["system:authenticated"]);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_token_review_cache_expires() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (reviewer, _token_file) = reviewer(mock_api_server(Arc::clone(&calls)).await, 0);
+
+        reviewer.verify("good-token").await.unwrap();
+        reviewer.verify("good-token").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_token_review_rejected_is_not_cached() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (reviewer, _token_file) = reviewer(mock_api_server(Arc::clone(&calls)).await, 60);
+
+        for _ in 0..2 {
+            let result = reviewer.verify("bad-token").await;
+            assert!(matches!(result, Err(ApiError::InvalidToken)));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_token_review_unreachable_api_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let (reviewer, _token_file) = reviewer(format!("http://{addr}"), 1);
+
+        let result = reviewer.verify("good-token").await;
+        assert!(matches!(result, Err(ApiError::TokenReview { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_token_review_rereads_token_file() {
+        async fn review(
+            State(seen): State<Arc<Mutex<Vec<String>>>>,
+            headers: HeaderMap,
+        ) -> Json<Value> {
+            let authorization = headers["authorization"].to_str().unwrap().to_string();
+            seen.lock().unwrap().push(authorization);
+            Json(json!({
+                "status": { "authenticated": true, "user": { "username": "admin" } }
+            }))
+        }
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let app = Router::new()
+            .route("/apis/authentication.k8s.io/v1/tokenreviews", post(review))
+            .with_state(Arc::clone(&seen));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let (reviewer, token_file) = reviewer(format!("http://{addr}"), 0);
+
+        reviewer.verify("a").await.unwrap();
+        // The kubelet rotated the projected token.
+        std::fs::write(&token_file.0, "rotated\n").unwrap();
+        reviewer.verify("b").await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), ["Bearer reviewer", "Bearer rotated"]);
+    }
+}
@@ -1,101 +1,842 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    fmt,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use snafu::OptionExt;
-use typst_as_lib::{TypstEngine, typst_kit_options::TypstKitFontOptions};
+use chrono::{DateTime, Datelike, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{OptionExt, ensure};
+use typst::{
+    Library, World, comemo,
+    diag::{FileError, FileResult, Severity, SourceDiagnostic, SourceResult, Warned},
+    ecow::EcoVec,
+    foundations::{Bytes, Datetime, Dict},
+    layout::{PageRange, PageRanges, PagedDocument},
+    syntax::{FileId, Source, Span, VirtualPath},
+    text::{Font, FontBook},
+    utils::LazyHash,
+};
+use typst_kit::fonts::Fonts;
+use typst_pdf::PdfOptions;
 
 use crate::{
     config::{Theme, TypstConfig},
-    error::{InvalidInputSnafu, Result, TypstPdfSnafu},
+    error::{InvalidInputSnafu, Result, TypstCompileSnafu},
 };
 
-pub fn generate_pdf(content: String, config: &TypstConfig, theme: &str) -> Result<Vec<u8>> {
-    let root_path = PathBuf::from(&config.assets_dir);
-    let theme_path = root_path.join(theme);
-    let theme: &Theme = config.themes.get(theme).context(InvalidInputSnafu {
-        reason: format!("Theme {} not found", theme),
-    })?;
+/// Virtual path of the document sent by the client.
+pub const MAIN_FILE: &str = "main.typ";
+/// Virtual path of the request data, readable with `json("data.json")`.
+pub const DATA_FILE: &str = "data.json";
+/// Largest raster scale of png output.
+pub const MAX_PIXEL_PER_PT: f32 = 10.0;
+/// Most pixels of a single png page, about 256 MiB of RGBA.
+const MAX_PAGE_PIXELS: f64 = (1u64 << 26) as f64;
+/// Compiles a memoized result survives unused; the cache is shared by all
+/// render workers, so it is aged rather than cleared.
+const MEMO_MAX_AGE: usize = 10;
 
-    let fonts: Vec<Vec<u8>> = theme
-        .icons
-        .iter()
-        .filter_map(|font| {
-            let font_path = config.icons.get(font).and_then(|p| {
-                let p = root_path.join(p);
-                if !p.exists() {
-                    return None;
+/// A typst error or warning, resolved to a file and a 1-based line/column range.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+    pub file: Option<String>,
+    pub range: Option<DiagnosticRange>,
+    pub hints: Vec<String>,
+    pub trace: Vec<DiagnosticTrace>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiagnosticTrace {
+    pub message: String,
+    pub file: Option<String>,
+    pub range: Option<DiagnosticRange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DiagnosticRange {
+    pub start: DiagnosticPosition,
+    pub end: DiagnosticPosition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DiagnosticPosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Diagnostic {
+    /// Rendered like `error: message` followed by its location, trace and
+    /// hints on indented lines.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            DiagnosticSeverity::Error => "error",
+            DiagnosticSeverity::Warning => "warning",
+        };
+        write!(f, "{severity}: {}", self.message)?;
+        write_location(f, self.file.as_deref(), self.range)?;
+        for trace in &self.trace {
+            write!(f, "\n  = {}", trace.message)?;
+            write_location(f, trace.file.as_deref(), trace.range)?;
+        }
+        for hint in &self.hints {
+            write!(f, "\n  = hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_location(
+    f: &mut fmt::Formatter<'_>,
+    file: Option<&str>,
+    range: Option<DiagnosticRange>,
+) -> fmt::Result {
+    match (file, range) {
+        (Some(file), Some(range)) => write!(
+            f,
+            "\n  --> {file}:{}:{}",
+            range.start.line, range.start.column
+        ),
+        (Some(file), None) => write!(f, "\n  --> {file}"),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Pdf,
+    Png,
+    Svg,
+}
+
+impl OutputFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Pdf => "application/pdf",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Svg => "image/svg+xml",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Pdf => "pdf",
+            OutputFormat::Png => "png",
+            OutputFormat::Svg => "svg",
+        }
+    }
+}
+
+/// Pages to export, written like typst's `--pages`, e.g. `1,3-5,8-`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PageSelection(Vec<PageRange>);
+
+impl PageSelection {
+    pub fn ranges(&self) -> PageRanges {
+        PageRanges::new(self.0.clone())
+    }
+}
+
+impl TryFrom<String> for PageSelection {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let page = |s: &str| -> std::result::Result<Option<NonZeroUsize>, String> {
+            let s = s.trim();
+            if s.is_empty() {
+                return Ok(None);
+            }
+            s.parse::<NonZeroUsize>()
+                .map(Some)
+                .map_err(|_| format!("invalid page number: {s}"))
+        };
+        let ranges = value
+            .split(',')
+            .map(|part| match part.split_once('-') {
+                Some((start, end)) => Ok(page(start)?..=page(end)?),
+                None => {
+                    let page = page(part)?.ok_or("empty page selection")?;
+                    Ok(Some(page)..=Some(page))
                 }
-                Some(p)
-            });
-            let Some(path) = font_path else {
-                tracing::debug!("Failed to find font: {}", font);
-                return None;
-            };
-            let Ok(bytes) = std::fs::read(path) else {
-                tracing::debug!("Failed to read font: {}", font);
-                return None;
+            })
+            .collect::<std::result::Result<Vec<_>, String>>()?;
+        Ok(Self(ranges))
+    }
+}
+
+/// How a report is exported once it compiled.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutputOptions {
+    #[serde(default)]
+    pub format: OutputFormat,
+    #[serde(default)]
+    pub pages: Option<PageSelection>,
+    /// Raster scale of png output.
+    #[serde(default = "default_pixel_per_pt")]
+    pub pixel_per_pt: f32,
+}
+
+fn default_pixel_per_pt() -> f32 {
+    2.0
+}
+
+impl OutputOptions {
+    /// Reject a raster scale typst cannot render.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.pixel_per_pt > 0.0 && self.pixel_per_pt <= MAX_PIXEL_PER_PT,
+            InvalidInputSnafu {
+                reason: format!("pixel_per_pt must be in (0, {MAX_PIXEL_PER_PT}]"),
+            }
+        );
+        Ok(())
+    }
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::default(),
+            pages: None,
+            pixel_per_pt: default_pixel_per_pt(),
+        }
+    }
+}
+
+pub enum Output {
+    Pdf(Vec<u8>),
+    Pages(Vec<RenderedPage>),
+}
+
+pub struct RenderedPage {
+    /// 1-based page number in the document.
+    pub number: usize,
+    pub data: Vec<u8>,
+}
+
+/// Prepared render contexts of all configured themes.
+///
+/// System fonts are scanned once and shared by every theme.
+pub struct Renderer {
+    themes: HashMap<String, ThemeContext>,
+    system_fonts: Arc<Fonts>,
+}
+
+/// Fonts, font book and template sources of a theme, loaded when the renderer
+/// is built.
+pub struct ThemeContext {
+    library: LazyHash<Library>,
+    book: LazyHash<FontBook>,
+    fonts: Vec<Font>,
+    system_fonts: Arc<Fonts>,
+    sources: HashMap<FileId, Source>,
+    /// Hash of the theme's font files and templates.
+    fingerprint: String,
+}
+
+/// The world of a single compilation: a theme context plus the request's `main.typ`.
+struct ReportWorld<'a> {
+    context: &'a ThemeContext,
+    main: FileId,
+    /// The client's `main.typ`, unset when rendering a theme template.
+    content: Option<Source>,
+    /// Library with the request data injected as `sys.inputs`.
+    library: Option<LazyHash<Library>>,
+    /// The request data, served as `data.json`.
+    data: Option<Bytes>,
+    now: DateTime<Utc>,
+}
+
+/// What to compile: the document and the data it is rendered with.
+#[derive(Debug, Clone)]
+pub struct ReportSource {
+    pub entry: Entry,
+    pub data: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Entry {
+    /// Typst source sent by the client, compiled as `main.typ`.
+    Content(String),
+    /// Name of a template of the theme, compiled as the entry file.
+    Template(String),
+}
+
+impl From<String> for ReportSource {
+    fn from(content: String) -> Self {
+        Self {
+            entry: Entry::Content(content),
+            data: None,
+        }
+    }
+}
+
+impl Renderer {
+    pub fn new(config: &TypstConfig) -> Self {
+        let system_fonts = Arc::new(Fonts::searcher().include_system_fonts(true).search());
+        tracing::debug!("found {} system fonts", system_fonts.fonts.len());
+        Self::with_system_fonts(config, system_fonts)
+    }
+
+    /// Load the themes of `config` again, keeping the scanned system fonts.
+    pub fn rebuild(&self, config: &TypstConfig) -> Self {
+        Self::with_system_fonts(config, Arc::clone(&self.system_fonts))
+    }
+
+    fn with_system_fonts(config: &TypstConfig, system_fonts: Arc<Fonts>) -> Self {
+        let themes = config
+            .themes
+            .iter()
+            .map(|(name, theme)| {
+                let context = ThemeContext::new(config, name, theme, Arc::clone(&system_fonts));
+                (name.to_owned(), context)
+            })
+            .collect();
+        Self {
+            themes,
+            system_fonts,
+        }
+    }
+
+    pub fn theme(&self, theme: &str) -> Result<&ThemeContext> {
+        self.themes.get(theme).context(InvalidInputSnafu {
+            reason: format!("Theme {} not found", theme),
+        })
+    }
+}
+
+impl ThemeContext {
+    fn new(config: &TypstConfig, name: &str, theme: &Theme, system_fonts: Arc<Fonts>) -> Self {
+        let root_path = PathBuf::from(&config.assets_dir);
+        let theme_path = root_path.join(name);
+        let mut fingerprint = Sha256::new();
+
+        let fonts: Vec<Font> = theme
+            .icons
+            .iter()
+            .filter_map(|font| {
+                let font_path = config.icons.get(font).and_then(|p| {
+                    let p = root_path.join(p);
+                    if !p.exists() {
+                        return None;
+                    }
+                    Some(p)
+                });
+                let Some(path) = font_path else {
+                    tracing::debug!("Failed to find font: {}", font);
+                    return None;
+                };
+                let Ok(bytes) = std::fs::read(path) else {
+                    tracing::debug!("Failed to read font: {}", font);
+                    return None;
+                };
+                fingerprint.update(font.as_bytes());
+                fingerprint.update(&bytes);
+                Some(Font::iter(Bytes::new(bytes)))
+            })
+            .flatten()
+            .collect();
+
+        let mut book = FontBook::new();
+        for font in fonts.iter() {
+            book.push(font.info().clone());
+        }
+        for index in 0..system_fonts.fonts.len() {
+            let Some(info) = system_fonts.book.info(index) else {
+                break;
             };
-            Some(bytes)
+            book.push(info.clone());
+        }
+
+        let mut templates: Vec<_> = theme.themplates.iter().collect();
+        templates.sort();
+        let sources = templates
+            .into_iter()
+            .filter_map(|(template_name, template_path)| {
+                let path = theme_path.join(template_path);
+                let Ok(temp) = std::fs::read_to_string(&path) else {
+                    tracing::debug!(
+                        "Failed to read template: {}, path: {:?}",
+                        template_name,
+                        &path
+                    );
+                    return None;
+                };
+                fingerprint.update(template_name.as_bytes());
+                fingerprint.update(temp.as_bytes());
+                let id = FileId::new(None, VirtualPath::new(template_name));
+                Some((id, Source::new(id, temp)))
+            })
+            .collect();
+
+        Self {
+            library: LazyHash::new(Library::default()),
+            book: LazyHash::new(book),
+            fonts,
+            system_fonts,
+            sources,
+            fingerprint: format!("{:x}", fingerprint.finalize()),
+        }
+    }
+
+    /// Changes whenever the theme's fonts or templates change.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    fn world(&self, source: ReportSource) -> Result<ReportWorld<'_>> {
+        let (main, content) = match source.entry {
+            Entry::Content(content) => {
+                let id = FileId::new(None, VirtualPath::new(MAIN_FILE));
+                (id, Some(Source::new(id, content)))
+            }
+            Entry::Template(template) => {
+                let id = FileId::new(None, VirtualPath::new(&template));
+                ensure!(
+                    self.sources.contains_key(&id),
+                    InvalidInputSnafu {
+                        reason: format!("Template {} not found", template),
+                    }
+                );
+                (id, None)
+            }
+        };
+        let (library, data) = match source.data {
+            Some(data) => {
+                let bytes = Bytes::from_string(data.to_string());
+                let inputs = match data {
+                    serde_json::Value::Object(_) => {
+                        serde_json::from_value::<Dict>(data).map_err(|e| {
+                            InvalidInputSnafu {
+                                reason: format!("Invalid data: {e}"),
+                            }
+                            .build()
+                        })?
+                    }
+                    _ => {
+                        return InvalidInputSnafu {
+                            reason: "Data must be a JSON object",
+                        }
+                        .fail();
+                    }
+                };
+                let library = Library::builder().with_inputs(inputs).build();
+                (Some(LazyHash::new(library)), Some(bytes))
+            }
+            None => (None, None),
+        };
+        Ok(ReportWorld {
+            context: self,
+            main,
+            content,
+            library,
+            data,
+            now: Utc::now(),
+        })
+    }
+}
+
+impl World for ReportWorld<'_> {
+    fn library(&self) -> &LazyHash<Library> {
+        self.library.as_ref().unwrap_or(&self.context.library)
+    }
+
+    fn book(&self) -> &LazyHash<FontBook> {
+        &self.context.book
+    }
+
+    fn main(&self) -> FileId {
+        self.main
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        if let Some(content) = self.content.as_ref().filter(|c| c.id() == id) {
+            return Ok(content.clone());
+        }
+        self.context
+            .sources
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| FileError::NotFound(id.vpath().as_rootless_path().into()))
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        if let Some(data) = &self.data
+            && id.package().is_none()
+            && id.vpath().as_rootless_path() == Path::new(DATA_FILE)
+        {
+            return Ok(data.clone());
+        }
+        self.source(id)
+            .map(|source| Bytes::from_string(source.text().to_owned()))
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        let fonts = &self.context.fonts;
+        match fonts.get(index) {
+            Some(font) => Some(font.clone()),
+            None => self
+                .context
+                .system_fonts
+                .fonts
+                .get(index - fonts.len())?
+                .get(),
+        }
+    }
+
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        // The offset comes from the document, so out of range ones fail the
+        // call instead of panicking.
+        let now = match offset {
+            Some(offset) => self.now.checked_add_signed(TimeDelta::try_hours(offset)?)?,
+            None => self.now,
+        };
+        let date = now.date_naive();
+        Datetime::from_ymd(date.year(), date.month() as u8, date.day() as u8)
+    }
+}
+
+fn locate(world: &dyn World, span: Span) -> (Option<String>, Option<DiagnosticRange>) {
+    let Some(id) = span.id() else {
+        return (None, None);
+    };
+    let file = id.vpath().as_rootless_path().display().to_string();
+    let range = world.source(id).ok().and_then(|source| {
+        let range = source.range(span)?;
+        let position = |byte| {
+            Some(DiagnosticPosition {
+                line: source.byte_to_line(byte)? + 1,
+                column: source.byte_to_column(byte)? + 1,
+            })
+        };
+        Some(DiagnosticRange {
+            start: position(range.start)?,
+            end: position(range.end)?,
         })
-        .collect();
-    let mut templates: Vec<(String, String)> = theme
-        .themplates
+    });
+    (Some(file), range)
+}
+
+impl Diagnostic {
+    fn from_source(world: &dyn World, diagnostic: &SourceDiagnostic) -> Self {
+        let (file, range) = locate(world, diagnostic.span);
+        let trace = diagnostic
+            .trace
+            .iter()
+            .map(|point| {
+                let (file, range) = locate(world, point.span);
+                DiagnosticTrace {
+                    message: point.v.to_string(),
+                    file,
+                    range,
+                }
+            })
+            .collect();
+        Self {
+            severity: match diagnostic.severity {
+                Severity::Error => DiagnosticSeverity::Error,
+                Severity::Warning => DiagnosticSeverity::Warning,
+            },
+            message: diagnostic.message.to_string(),
+            file,
+            range,
+            hints: diagnostic.hints.iter().map(|h| h.to_string()).collect(),
+            trace,
+        }
+    }
+}
+
+pub fn generate_pdf(
+    source: impl Into<ReportSource>,
+    renderer: &Renderer,
+    theme: &str,
+) -> Result<Vec<u8>> {
+    match generate(source.into(), renderer, theme, &OutputOptions::default())? {
+        Output::Pdf(pdf) => Ok(pdf),
+        Output::Pages(_) => unreachable!("pdf output is a single file"),
+    }
+}
+
+pub fn generate(
+    source: ReportSource,
+    renderer: &Renderer,
+    theme: &str,
+    options: &OutputOptions,
+) -> Result<Output> {
+    let world = renderer.theme(theme)?.world(source)?;
+
+    let Warned { output, warnings } = typst::compile::<PagedDocument>(&world);
+    let result = output
+        .and_then(|document| export(&document, options))
+        .map_err(|errors| {
+            let diagnostics: Vec<Diagnostic> = errors
+                .iter()
+                .chain(warnings.iter())
+                .map(|d| Diagnostic::from_source(&world, d))
+                .collect();
+            TypstCompileSnafu { diagnostics }.build()
+        });
+    comemo::evict(MEMO_MAX_AGE);
+    let output = result?;
+    if let Output::Pages(pages) = &output
+        && pages.is_empty()
+    {
+        return InvalidInputSnafu {
+            reason: "No pages selected",
+        }
+        .fail();
+    }
+    Ok(output)
+}
+
+fn export(document: &PagedDocument, options: &OutputOptions) -> SourceResult<Output> {
+    let ranges = options.pages.as_ref().map(PageSelection::ranges);
+    if options.format == OutputFormat::Pdf {
+        let pdf_options = PdfOptions {
+            page_ranges: ranges,
+            ..Default::default()
+        };
+        return typst_pdf::pdf(document, &pdf_options).map(Output::Pdf);
+    }
+    document
+        .pages
         .iter()
-        .filter_map(|(template_name, template_path)| {
-            let path = theme_path.join(template_path);
-            let Ok(temp) = std::fs::read_to_string(&path) else {
-                tracing::debug!(
-                    "Failed to read template: {}, path: {:?}",
-                    template_name,
-                    &path
-                );
-                return None;
+        .enumerate()
+        .filter(|(index, _)| {
+            ranges
+                .as_ref()
+                .is_none_or(|r| r.includes_page_index(*index))
+        })
+        .map(|(index, page)| {
+            let data = match options.format {
+                OutputFormat::Svg => typst_svg::svg(page).into_bytes(),
+                _ => {
+                    let size = page.frame.size();
+                    let scale = f64::from(options.pixel_per_pt);
+                    let pixels = (size.x.to_pt() * scale).round().max(1.0)
+                        * (size.y.to_pt() * scale).round().max(1.0);
+                    if pixels.is_nan() || pixels > MAX_PAGE_PIXELS {
+                        return Err(EcoVec::from([SourceDiagnostic::error(
+                            Span::detached(),
+                            format!(
+                                "Page {} is too large to render as png, lower pixel_per_pt",
+                                index + 1
+                            ),
+                        )]));
+                    }
+                    typst_render::render(page, options.pixel_per_pt)
+                    .encode_png()
+                    .map_err(|e| {
+                        EcoVec::from([SourceDiagnostic::error(
+                            Span::detached(),
+                            format!("Could not encode png. {e}"),
+                        )])
+                    })?
+                }
             };
-            Some((template_name.to_owned(), temp))
+            Ok(RenderedPage {
+                number: index + 1,
+                data,
+            })
         })
-        .collect();
-    templates.push(("main.typ".to_string(), content));
-    let templates = templates
-        .iter()
-        .map(|(a, b)| (a.as_str(), b.as_str()))
-        .collect::<Vec<(&str, &str)>>();
+        .collect::<SourceResult<Vec<_>>>()
+        .map(Output::Pages)
+}
 
-    let engine = TypstEngine::builder()
-        .search_fonts_with(TypstKitFontOptions::default().include_system_fonts(true))
-        .fonts(fonts)
-        .with_static_source_file_resolver(templates)
-        .build();
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
 
-    let options = Default::default();
+    use super::*;
+    use crate::error::Error;
 
-    engine.compile("main.typ").output.map_or_else(
-        |e| {
-            TypstPdfSnafu {
-                message: format!("typst::compile() returned an error! {:?}", e),
-            }
-            .fail()
-        },
-        |d| match typst_pdf::pdf(&d, &options) {
-            Ok(pdf) => Ok(pdf),
-            Err(e) => TypstPdfSnafu {
-                message: format!("Could not generate pdf. {:?}", e),
+    fn renderer() -> Renderer {
+        let mut themes = HashMap::new();
+        themes.insert(
+            "default".to_string(),
+            Theme {
+                themplates: HashMap::from([(
+                    "template.typ".to_string(),
+                    "template/template.typ".to_string(),
+                )]),
+                ..Default::default()
+            },
+        );
+        Renderer::new(&TypstConfig {
+            assets_dir: "./examples/demo/assets".to_string(),
+            assets_s3: None,
+            themes,
+            icons: HashMap::new(),
+            render: Default::default(),
+            lenient: false,
+        })
+    }
+
+    #[test]
+    fn test_generate_pdf_unknown_theme() {
+        let result = generate_pdf("= Hello".to_string(), &renderer(), "missing");
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_today_out_of_range_offset() {
+        let renderer = renderer();
+        let world = renderer.themes["default"]
+            .world("= Hello".to_string().into())
+            .unwrap();
+        assert!(world.today(Some(9)).is_some());
+        assert!(world.today(Some(9_999_999_999_999)).is_none());
+        assert!(world.today(Some(i64::MAX)).is_none());
+        assert!(world.today(Some(-9_999_999_999)).is_none());
+    }
+
+    #[test]
+    fn test_page_selection_parse() {
+        let selection = PageSelection::try_from("1,3-4,6-".to_string()).unwrap();
+        let ranges = selection.ranges();
+        let included: Vec<usize> = (0..8).filter(|i| ranges.includes_page_index(*i)).collect();
+        assert_eq!(included, vec![0, 2, 3, 5, 6, 7]);
+        assert!(PageSelection::try_from("0".to_string()).is_err());
+        assert!(PageSelection::try_from("a-2".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_generate_png_pages() {
+        let content =
+            "#set page(width: 20pt, height: 10pt)\na#pagebreak()b#pagebreak()c".to_string();
+        let options = OutputOptions {
+            format: OutputFormat::Png,
+            pages: Some(PageSelection::try_from("2-".to_string()).unwrap()),
+            pixel_per_pt: 1.0,
+        };
+        let Output::Pages(pages) =
+            generate(content.into(), &renderer(), "default", &options).unwrap()
+        else {
+            panic!("Expected pages");
+        };
+        let numbers: Vec<usize> = pages.iter().map(|p| p.number).collect();
+        assert_eq!(numbers, vec![2, 3]);
+        assert!(pages[0].data.starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn test_output_options_validate() {
+        for pixel_per_pt in [0.0, -1.0, f32::NAN, f32::INFINITY, 10.5] {
+            let options = OutputOptions {
+                pixel_per_pt,
+                ..Default::default()
+            };
+            assert!(matches!(options.validate(), Err(Error::InvalidInput { .. })));
+        }
+        assert!(OutputOptions::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_generate_png_page_too_large() {
+        let options = OutputOptions {
+            format: OutputFormat::Png,
+            pixel_per_pt: MAX_PIXEL_PER_PT,
+            ..Default::default()
+        };
+        let content = "#set page(width: 5000pt, height: 5000pt)\na".to_string();
+        let result = generate(content.into(), &renderer(), "default", &options);
+        assert!(matches!(result, Err(Error::TypstCompile { .. })));
+    }
+
+    #[test]
+    fn test_generate_no_pages_selected() {
+        let options = OutputOptions {
+            format: OutputFormat::Svg,
+            pages: Some(PageSelection::try_from("5".to_string()).unwrap()),
+            ..Default::default()
+        };
+        let result = generate("a".to_string().into(), &renderer(), "default", &options);
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_generate_with_data() {
+        let content = r#"
+            #import "template.typ": h1
+            #assert.eq(sys.inputs.cluster, "a#]b")
+            #assert.eq(json("data.json").nodes.len(), 2)
+            #h1(sys.inputs.cluster)
+        "#;
+        let source = ReportSource {
+            entry: Entry::Content(content.to_string()),
+            data: Some(serde_json::json!({ "cluster": "a#]b", "nodes": [1, 2] })),
+        };
+        let result = generate(source, &renderer(), "default", &OutputOptions::default());
+        assert!(matches!(result, Ok(Output::Pdf(_))));
+    }
+
+    #[test]
+    fn test_generate_template_entry() {
+        let source = ReportSource {
+            entry: Entry::Template("template.typ".to_string()),
+            data: Some(serde_json::json!({})),
+        };
+        let result = generate(source, &renderer(), "default", &OutputOptions::default());
+        assert!(matches!(result, Ok(Output::Pdf(_))));
+
+        let source = ReportSource {
+            entry: Entry::Template("missing.typ".to_string()),
+            data: None,
+        };
+        let result = generate(source, &renderer(), "default", &OutputOptions::default());
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_generate_data_must_be_object() {
+        let source = ReportSource {
+            entry: Entry::Content("a".to_string()),
+            data: Some(serde_json::json!([1, 2])),
+        };
+        let result = generate(source, &renderer(), "default", &OutputOptions::default());
+        assert!(matches!(result, Err(Error::InvalidInput { .. })));
+    }
+
+    #[test]
+    fn test_generate_pdf_diagnostics() {
+        let content = "= Hello\n\n#let x = 1\n#unknown-fn()\n".to_string();
+        let result = generate_pdf(content, &renderer(), "default");
+        let Err(Error::TypstCompile { diagnostics }) = result else {
+            panic!("Expected TypstCompile error");
+        };
+        let error = diagnostics
+            .iter()
+            .find(|d| d.severity == DiagnosticSeverity::Error)
+            .unwrap();
+        assert_eq!(error.file.as_deref(), Some(MAIN_FILE));
+        let range = error.range.unwrap();
+        assert_eq!(range.start, DiagnosticPosition { line: 4, column: 2 });
+        assert_eq!(
+            range.end,
+            DiagnosticPosition {
+                line: 4,
+                column: 12
             }
-            .fail(),
-        },
-    )
-
-    // let pdf = typst_pdf::pdf(&doc, &options).expect("Could not generate pdf.");
-}
-
-// pub fn generate_pdf_new(content: String, assets_dir: &str) -> Result<Vec<u8>> {
-//     let world = TypstWrapperWorld::new(assets_dir.to_owned(), content.to_owned());
-//     let pdf = typst::compile(&world)
-//         .output
-//         .and_then(|d| typst_pdf::pdf(&d, &PdfOptions::default()));
-//     match pdf {
-//         Ok(pdf) => Ok(pdf),
-//         Err(e) => TypstPdfSnafu {
-//             message: format!("{:?}", e),
-//         }
-//         .fail(),
-//     }
-// }
+        );
+        assert!(
+            error
+                .to_string()
+                .starts_with("error: unknown variable: unknown-fn\n  --> main.typ:4:2")
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::path::Path;
+
+use typst::{foundations::Bytes, text::Font};
+
+use crate::{
+    config::{Theme, TypstConfig},
+    error::{InvalidConfigSnafu, Result},
+};
+
+/// Theme every report falls back to.
+pub const DEFAULT_THEME: &str = "default";
+
+/// Fonts and templates of `theme` that do not resolve; with `parse_fonts`
+/// font files must also parse.
+pub fn theme_problems(
+    config: &TypstConfig,
+    name: &str,
+    theme: &Theme,
+    parse_fonts: bool,
+) -> Vec<String> {
+    let root_path = Path::new(&config.assets_dir);
+    let mut problems = vec![];
+    for font in &theme.icons {
+        let Some(path) = config.icons.get(font) else {
+            problems.push(format!("theme {name}: font {font} is not defined in icons"));
+            continue;
+        };
+        let font_path = root_path.join(path);
+        if !font_path.is_file() {
+            problems.push(format!("theme {name}: font {font} not found at {path}"));
+        } else if parse_fonts {
+            let parses = std::fs::read(&font_path)
+                .is_ok_and(|bytes| Font::iter(Bytes::new(bytes)).next().is_some());
+            if !parses {
+                problems.push(format!("theme {name}: font {font} at {path} is not a font"));
+            }
+        }
+    }
+    let theme_path = root_path.join(name);
+    let mut templates: Vec<_> = theme.themplates.iter().collect();
+    templates.sort();
+    for (template, path) in templates {
+        if !theme_path.join(path).is_file() {
+            problems.push(format!(
+                "theme {name}: template {template} not found at {path}"
+            ));
+        }
+    }
+    problems
+}
+
+/// Every problem of `config`, themes in name order.
+pub fn config_problems(config: &TypstConfig) -> Vec<String> {
+    let mut problems = vec![];
+    if !Path::new(&config.assets_dir).is_dir() {
+        problems.push(format!(
+            "assets_dir {} is not a directory",
+            config.assets_dir
+        ));
+    }
+    if !config.themes.contains_key(DEFAULT_THEME) {
+        problems.push(format!("theme {DEFAULT_THEME} is not configured"));
+    }
+    let mut themes: Vec<_> = config.themes.iter().collect();
+    themes.sort_by_key(|(name, _)| *name);
+    for (name, theme) in themes {
+        problems.extend(theme_problems(config, name, theme, true));
+    }
+    problems
+}
+
+/// Refuse a broken config, or only warn about it when `lenient` is set.
+pub fn validate(config: &TypstConfig) -> Result<()> {
+    let problems = config_problems(config);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    if config.lenient {
+        for problem in &problems {
+            tracing::warn!("typst config: {}", problem);
+        }
+        return Ok(());
+    }
+    InvalidConfigSnafu { problems }.fail()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::error::Error;
+
+    fn config() -> TypstConfig {
+        let font_path = std::env::temp_dir().join(format!("not-a-font-{}.ttf", std::process::id()));
+        std::fs::write(&font_path, "not a font").unwrap();
+        TypstConfig {
+            assets_dir: "./examples/demo/assets".to_string(),
+            assets_s3: None,
+            themes: HashMap::from([(
+                "custom".to_string(),
+                Theme {
+                    icons: vec![
+                        "Missing".to_string(),
+                        "Undefined".to_string(),
+                        "Broken".to_string(),
+                    ],
+                    themplates: HashMap::from([
+                        (
+                            "template.typ".to_string(),
+                            "template/template.typ".to_string(),
+                        ),
+                        ("other.typ".to_string(), "template/other.typ".to_string()),
+                    ]),
+                },
+            )]),
+            icons: HashMap::from([
+                ("Missing".to_string(), "fonts/missing.ttf".to_string()),
+                ("Broken".to_string(), font_path.display().to_string()),
+            ]),
+            render: Default::default(),
+            lenient: false,
+        }
+    }
+
+    #[test]
+    fn test_config_problems_reported_at_once() {
+        assert_eq!(
+            config_problems(&config()),
+            vec![
+                "theme default is not configured",
+                "theme custom: font Missing not found at fonts/missing.ttf",
+                "theme custom: font Undefined is not defined in icons",
+                format!(
+                    "theme custom: font Broken at {} is not a font",
+                    config().icons["Broken"]
+                )
+                .as_str(),
+                "theme custom: template other.typ not found at template/other.typ",
+                "theme custom: template template.typ not found at template/template.typ",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_lenient() {
+        let mut config = config();
+        match validate(&config) {
+            Err(Error::InvalidConfig { problems }) => assert_eq!(problems.len(), 6),
+            other => panic!("unexpected result: {other:?}"),
+        }
+        config.lenient = true;
+        assert!(validate(&config).is_ok());
+    }
+
+    #[test]
+    fn test_validate_demo_config() {
+        let config = TypstConfig {
+            assets_dir: "./examples/demo/assets".to_string(),
+            assets_s3: None,
+            themes: HashMap::from([(
+                DEFAULT_THEME.to_string(),
+                Theme {
+                    icons: vec![],
+                    themplates: HashMap::from([(
+                        "template.typ".to_string(),
+                        "template/template.typ".to_string(),
+                    )]),
+                },
+            )]),
+            icons: HashMap::new(),
+            render: Default::default(),
+            lenient: false,
+        };
+        assert!(validate(&config).is_ok());
+    }
+}
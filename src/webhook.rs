@@ -0,0 +1,359 @@
+use std::time::Duration;
+
+use axum::http::HeaderName;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use snafu::ResultExt;
+
+use crate::{
+    config::JobsConfig,
+    error::{Result, WebhookSnafu},
+    jobs::{JobInfo, JobStatus},
+    typst_lib::Diagnostic,
+};
+
+/// `sha256=<hex HMAC-SHA256 of "<timestamp>.<body>">`, sent when the job has
+/// a secret.
+pub const SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-kube-eye-signature");
+
+/// Unix seconds a delivery was signed at; receivers reject old ones to stop
+/// replays.
+pub const TIMESTAMP_HEADER: HeaderName = HeaderName::from_static("x-kube-eye-timestamp");
+
+/// Wall-clock limit of a single delivery attempt.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest pause between two delivery attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Body POSTed to a job's `callback_url` once it finished.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobNotification {
+    pub job_id: String,
+    pub status: JobStatus,
+    pub name: String,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Where the result is downloaded from, set when the job is done.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl JobNotification {
+    /// Notification of `job`, its download link relative to `public_url`.
+    pub fn new(job: &JobInfo, public_url: Option<&str>) -> Self {
+        let download_url = (job.status == JobStatus::Done).then(|| {
+            format!(
+                "{}/api/reports/jobs/{}/file",
+                public_url.unwrap_or_default().trim_end_matches('/'),
+                job.id
+            )
+        });
+        Self {
+            job_id: job.id.clone(),
+            status: job.status,
+            name: job.name.clone(),
+            finished_at: job.finished_at,
+            download_url,
+            sha256: job.sha256.clone(),
+            size: job.size,
+            error: job.error.clone(),
+            diagnostics: job.diagnostics.clone(),
+        }
+    }
+}
+
+/// Where and how a job reports back.
+#[derive(Debug, Clone)]
+pub struct Callback {
+    pub url: reqwest::Url,
+    pub secret: Option<String>,
+}
+
+/// Hex encoded HMAC-SHA256 of `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Signature of a delivery of `body` at `timestamp`.
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let message = [timestamp.to_string().as_bytes(), b".", body].concat();
+    format!("sha256={}", sign(secret, &message))
+}
+
+/// Whether `host` is one of `hosts`, where `*.example.com` matches any
+/// subdomain of `example.com`.
+pub fn host_allowed(hosts: &[String], host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    hosts.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        match allowed.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => host == allowed,
+        }
+    })
+}
+
+/// Delivers job notifications, retrying with exponential backoff.
+pub struct Notifier {
+    client: reqwest::Client,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Notifier {
+    pub fn new(config: &JobsConfig) -> Result<Self> {
+        // A redirect could lead the signed body past the callback host check,
+        // so a 3xx answer counts as a failed delivery.
+        let client = reqwest::Client::builder()
+            .timeout(ATTEMPT_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .context(WebhookSnafu)?;
+        Ok(Self {
+            client,
+            retries: config.callback_retries,
+            backoff: Duration::from_millis(config.callback_backoff_ms),
+        })
+    }
+
+    /// POST `notification` to `callback` until it answers 2xx or the retries
+    /// run out, returning whether it was delivered.
+    pub async fn notify(&self, callback: &Callback, notification: &JobNotification) -> bool {
+        let body = match serde_json::to_vec(notification) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("serialize job notification error: {}", e);
+                return false;
+            }
+        };
+        let mut backoff = self.backoff;
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            let mut request = self
+                .client
+                .post(callback.url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = &callback.secret {
+                let timestamp = Utc::now().timestamp();
+                request = request
+                    .header(TIMESTAMP_HEADER, timestamp)
+                    .header(SIGNATURE_HEADER, signature(secret, timestamp, &body));
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    tracing::info!("notified {} of job {}", callback.url, notification.job_id);
+                    return true;
+                }
+                Ok(response) => tracing::warn!(
+                    "callback {} of job {} answered {} (attempt {})",
+                    callback.url,
+                    notification.job_id,
+                    response.status(),
+                    attempt + 1
+                ),
+                Err(e) => tracing::warn!(
+                    "callback {} of job {} failed (attempt {}): {}",
+                    callback.url,
+                    notification.job_id,
+                    attempt + 1,
+                    e
+                ),
+            }
+        }
+        tracing::error!(
+            "gave up notifying {} of job {}",
+            callback.url,
+            notification.job_id
+        );
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{
+        Router,
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, StatusCode, header::LOCATION},
+        routing::post,
+    };
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+    /// A stand-in receiver failing the first `failures` deliveries.
+    async fn receiver(failures: usize, received: Received) -> reqwest::Url {
+        async fn receive(
+            State((calls, failures, received)): State<(Arc<AtomicUsize>, usize, Received)>,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> StatusCode {
+            if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+            received.lock().unwrap().push((headers, body));
+            StatusCode::NO_CONTENT
+        }
+
+        let app = Router::new().route("/hook", post(receive)).with_state((
+            Arc::new(AtomicUsize::new(0)),
+            failures,
+            received,
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}/hook").parse().unwrap()
+    }
+
+    fn notifier(callback_retries: u32) -> Notifier {
+        Notifier::new(&JobsConfig {
+            dir: String::new(),
+            retention_secs: 60,
+            public_url: None,
+            callback_retries,
+            callback_backoff_ms: 10,
+            callback_hosts: vec![],
+            max_per_user: 10,
+            queue_timeout_secs: 60,
+        })
+        .unwrap()
+    }
+
+    fn notification() -> JobNotification {
+        JobNotification {
+            job_id: "job".to_string(),
+            status: JobStatus::Done,
+            name: "weekly".to_string(),
+            finished_at: None,
+            download_url: Some("/api/reports/jobs/job/file".to_string()),
+            sha256: Some("abc".to_string()),
+            size: Some(3),
+            error: None,
+            diagnostics: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notify_retries_and_signs() {
+        let received = Received::default();
+        let callback = Callback {
+            url: receiver(2, Arc::clone(&received)).await,
+            secret: Some("secret".to_string()),
+        };
+        assert!(notifier(3).notify(&callback, &notification()).await);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let (headers, body) = &received[0];
+        let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert!((Utc::now().timestamp() - timestamp).abs() < 60);
+        assert_eq!(
+            headers.get(SIGNATURE_HEADER).unwrap().to_str().unwrap(),
+            signature("secret", timestamp, body)
+        );
+        let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(body["job_id"], "job");
+        assert_eq!(body["status"], "done");
+        assert_eq!(body["sha256"], "abc");
+    }
+
+    #[tokio::test]
+    async fn test_notify_gives_up() {
+        let received = Received::default();
+        let callback = Callback {
+            url: receiver(usize::MAX, Arc::clone(&received)).await,
+            secret: None,
+        };
+        assert!(!notifier(2).notify(&callback, &notification()).await);
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    /// Listens on a fresh port, answering every request with `app`.
+    async fn serve(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn test_notify_does_not_follow_redirects() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let target = serve(Router::new().fallback({
+            let received = Arc::clone(&received);
+            move || async move {
+                received.fetch_add(1, Ordering::SeqCst);
+                StatusCode::NO_CONTENT
+            }
+        }))
+        .await;
+        for status in [StatusCode::FOUND, StatusCode::TEMPORARY_REDIRECT] {
+            let location = format!("{target}/latest/meta-data");
+            let hook = serve(Router::new().route(
+                "/hook",
+                post(move || async move { (status, [(LOCATION, location)]) }),
+            ))
+            .await;
+            let callback = Callback {
+                url: format!("{hook}/hook").parse().unwrap(),
+                secret: Some("secret".to_string()),
+            };
+            assert!(!notifier(1).notify(&callback, &notification()).await);
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            signature("Jefe", 1700000000, b"{}"),
+            format!("sha256={}", sign("Jefe", b"1700000000.{}"))
+        );
+    }
+
+    #[test]
+    fn test_host_allowed() {
+        let hosts = vec!["hooks.example.com".to_string(), "*.example.org".to_string()];
+        assert!(host_allowed(&hosts, "hooks.example.com"));
+        assert!(host_allowed(&hosts, "HOOKS.example.com"));
+        assert!(host_allowed(&hosts, "a.b.example.org"));
+        assert!(!host_allowed(&hosts, "example.org"));
+        assert!(!host_allowed(&hosts, "badexample.org"));
+        assert!(!host_allowed(&hosts, "example.com"));
+        assert!(!host_allowed(&hosts, "169.254.169.254"));
+        assert!(!host_allowed(&[], "hooks.example.com"));
+    }
+}
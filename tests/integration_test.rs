@@ -3,24 +3,49 @@ use std::sync::Arc;
 
 use arc_swap::ArcSwap;
 use axum::{
-    body::Body,
-    http::{Request, StatusCode},
+    body::{Body, Bytes, to_bytes},
+    http::{HeaderMap, Request, StatusCode},
+    routing::post,
     Router,
 };
 use serde_json::json;
+use std::io::Read;
+use zip::ZipArchive;
 use tower::ServiceExt;
 
 use kube_eye_export_server::{
     client_config::ClientConfig,
-    config::{ServerConfig, TypstConfig, Theme},
-    server::Server,
+    auth::sha256_hex,
+    config::{
+        ArchiveConfig, AuthConfig, AuthorizationConfig, AuthorizationRule, CacheConfig, JobsConfig,
+        RateLimitConfig, RouteGroup, ServerConfig, StaticToken, TypstConfig, Theme,
+    },
+    server::{Server, ServerState},
+    webhook,
 };
+use sha2::{Digest, Sha256};
 
 fn create_test_server_config() -> ServerConfig {
     ServerConfig {
         host: "127.0.0.1".to_string(),
         port: 8080,
         public_dir_dist: vec![],
+        auth: AuthConfig {
+            tokens: vec![StaticToken {
+                sha256: sha256_hex("token"),
+                user_id: "tester".to_string(),
+                groups: vec![],
+            }],
+            ..Default::default()
+        },
+        authorization: AuthorizationConfig::default(),
+        rate_limit: None,
+        drain_timeout_secs: 30,
+        shutdown_delay_secs: 0,
+        tls: None,
+        listeners: vec![],
+        jobs: None,
+        archive: None,
     }
 }
 
@@ -33,8 +58,11 @@ fn create_test_typst_config() -> TypstConfig {
 
     TypstConfig {
         assets_dir: "./assets".to_string(),
+        assets_s3: None,
         themes,
         icons: HashMap::new(),
+        render: Default::default(),
+        lenient: false,
     }
 }
 
@@ -42,22 +70,1067 @@ fn create_test_client_config() -> Arc<ArcSwap<ClientConfig>> {
     Arc::new(ArcSwap::from_pointee(ClientConfig::default()))
 }
 
+fn create_test_router() -> Router {
+    let server = Server::new(create_test_server_config(), create_test_client_config());
+    let state = ServerState::new(
+        &server.config,
+        create_test_client_config(),
+        create_test_typst_config(),
+    )
+    .unwrap();
+    server.router(state)
+}
+
 #[tokio::test]
 async fn test_health_endpoint() {
-    let server = Server::new(create_test_server_config(), create_test_client_config());
-    let typst_config = create_test_typst_config();
-    
-    // Note: We can't easily test the full server.run() in integration tests
-    // as it would require binding to a port. Instead, we test individual handlers.
-    
-    // This is a simplified test structure. In a real scenario, you'd want to
-    // construct the router and test it directly.
+    let request = Request::builder()
+        .uri("/health")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
 }
 
 #[tokio::test]
 async fn test_version_endpoint() {
-    // Similar limitation as above - this would require access to the router
-    // constructed in server.run()
+    let request = Request::builder()
+        .uri("/version")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_report_endpoint_reuses_theme_context() {
+    let router = create_test_router();
+    for _ in 0..2 {
+        let request = Request::builder()
+            .uri("/api/report")
+            .method("POST")
+            .header("Authorization", "Bearer token")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                json!({ "name": "report", "content": "= Hello" }).to_string(),
+            ))
+            .unwrap();
+
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/pdf"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_report_endpoint_cached() {
+    let server = Server::new(create_test_server_config(), create_test_client_config());
+    let mut typst_config = create_test_typst_config();
+    typst_config.render.cache = Some(CacheConfig::default());
+    let state = ServerState::new(&server.config, create_test_client_config(), typst_config)
+        .unwrap();
+    let router = server.router(state);
+    let body = json!({ "name": "report", "content": "= Cached" });
+
+    let first = router.clone().oneshot(report_request(body.clone())).await.unwrap();
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(first.headers().get("x-cache").unwrap(), "miss");
+    let etag = first.headers().get("etag").unwrap().clone();
+    let first = to_bytes(first.into_body(), usize::MAX).await.unwrap();
+
+    let second = router.clone().oneshot(report_request(body)).await.unwrap();
+    assert_eq!(second.headers().get("x-cache").unwrap(), "hit");
+    assert_eq!(second.headers().get("etag").unwrap(), &etag);
+    assert_eq!(to_bytes(second.into_body(), usize::MAX).await.unwrap(), first);
+
+    let other = json!({ "name": "report", "content": "= Cached", "format": "svg" });
+    let other = router.oneshot(report_request(other)).await.unwrap();
+    assert_eq!(other.headers().get("x-cache").unwrap(), "miss");
+    assert_ne!(other.headers().get("etag").unwrap(), &etag);
+}
+
+fn create_jobs_router(dir: &str) -> Router {
+    let mut config = create_test_server_config();
+    config.jobs = Some(JobsConfig {
+        dir: dir.to_string(),
+        retention_secs: 60,
+        public_url: Some("https://export.example.com".to_string()),
+        callback_retries: 2,
+        callback_backoff_ms: 10,
+        callback_hosts: vec!["127.0.0.1".to_string()],
+        max_per_user: 10,
+        queue_timeout_secs: 60,
+    });
+    let server = Server::new(config, create_test_client_config());
+    let state = ServerState::new(
+        &server.config,
+        create_test_client_config(),
+        create_test_typst_config(),
+    )
+    .unwrap();
+    server.router(state)
+}
+
+fn job_request(method: &str, uri: &str) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", "Bearer token")
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn wait_for_job(router: &Router, id: &str) -> serde_json::Value {
+    for _ in 0..100 {
+        let response = router
+            .clone()
+            .oneshot(job_request("GET", &format!("/api/reports/jobs/{id}")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        if job["status"] == "done" || job["status"] == "failed" {
+            return job;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("job {id} did not finish");
+}
+
+#[tokio::test]
+async fn test_report_job_lifecycle() {
+    let dir = std::env::temp_dir().join(format!("jobs-it-{}", std::process::id()));
+    let router = create_jobs_router(&dir.display().to_string());
+
+    let create = Request::builder()
+        .uri("/api/reports/jobs")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({ "name": "weekly", "content": "= Weekly" }).to_string(),
+        ))
+        .unwrap();
+    let response = router.clone().oneshot(create).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let id = job["id"].as_str().unwrap().to_string();
+
+    let job = wait_for_job(&router, &id).await;
+    assert_eq!(job["status"], "done");
+
+    let response = router
+        .clone()
+        .oneshot(job_request("GET", &format!("/api/reports/jobs/{id}/file")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/pdf"
+    );
+    let pdf = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(pdf.starts_with(b"%PDF"));
+    assert_eq!(job["size"], pdf.len());
+
+    let response = router
+        .clone()
+        .oneshot(job_request("DELETE", &format!("/api/reports/jobs/{id}")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = router
+        .oneshot(job_request("GET", &format!("/api/reports/jobs/{id}")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_report_job_failure_diagnostics() {
+    let dir = std::env::temp_dir().join(format!("jobs-it-failed-{}", std::process::id()));
+    let router = create_jobs_router(&dir.display().to_string());
+
+    let create = Request::builder()
+        .uri("/api/reports/jobs")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({ "name": "broken", "content": "#unknown-fn()" }).to_string(),
+        ))
+        .unwrap();
+    let response = router.clone().oneshot(create).await.unwrap();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let id = job["id"].as_str().unwrap().to_string();
+
+    let job = wait_for_job(&router, &id).await;
+    assert_eq!(job["status"], "failed");
+    assert!(!job["diagnostics"].as_array().unwrap().is_empty());
+
+    let response = router
+        .oneshot(job_request("GET", &format!("/api/reports/jobs/{id}/file")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+fn archive_request(token: &str, uri: &str) -> Request<Body> {
+    Request::builder()
+        .uri(uri)
+        .header("Authorization", format!("Bearer {token}"))
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn json_response(router: &Router, request: Request<Body>) -> serde_json::Value {
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_report_archive() {
+    let dir = std::env::temp_dir().join(format!("archive-it-{}", std::process::id()));
+    let mut config = create_test_server_config();
+    config.auth.tokens.push(StaticToken {
+        sha256: sha256_hex("other-token"),
+        user_id: "other".to_string(),
+        groups: vec![],
+    });
+    config.authorization.rules = vec![
+        AuthorizationRule {
+            users: vec!["tester".to_string()],
+            themes: vec!["*".to_string()],
+            archive: true,
+            ..Default::default()
+        },
+        AuthorizationRule {
+            users: vec!["other".to_string()],
+            themes: vec!["*".to_string()],
+            ..Default::default()
+        },
+    ];
+    config.archive = Some(ArchiveConfig {
+        dir: dir.display().to_string(),
+        s3: None,
+        max_age_secs: None,
+        max_total_bytes: None,
+    });
+    let server = Server::new(config, create_test_client_config());
+    let state = ServerState::new(
+        &server.config,
+        create_test_client_config(),
+        create_test_typst_config(),
+    )
+    .unwrap();
+    let router = server.router(state.clone());
+
+    let request = Request::builder()
+        .uri("/api/report")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({ "name": "weekly", "content": "= Weekly" }).to_string(),
+        ))
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let pdf = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    // The report is archived in the background, holding an in-flight guard.
+    state.in_flight.idle().await;
+
+    let reports = json_response(&router, archive_request("token", "/api/reports")).await;
+    let reports = reports.as_array().unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0]["user"], "tester");
+    assert_eq!(reports[0]["name"], "weekly");
+    assert_eq!(reports[0]["theme"], "default");
+    assert_eq!(reports[0]["size"], pdf.len());
+    assert_eq!(reports[0]["sha256"], format!("{:x}", Sha256::digest(&pdf)));
+    let id = reports[0]["id"].as_str().unwrap();
+
+    let filtered = json_response(
+        &router,
+        archive_request("token", "/api/reports?theme=internal"),
+    )
+    .await;
+    assert!(filtered.as_array().unwrap().is_empty());
+
+    let response = router
+        .clone()
+        .oneshot(archive_request("token", &format!("/api/reports/{id}/file")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/pdf"
+    );
+    let archived = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(archived, pdf);
+
+    // Other users only see their own reports.
+    let own = json_response(&router, archive_request("other-token", "/api/reports")).await;
+    assert!(own.as_array().unwrap().is_empty());
+    let response = router
+        .clone()
+        .oneshot(archive_request("other-token", &format!("/api/reports/{id}")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = router
+        .oneshot(archive_request("other-token", "/api/reports?user=tester"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// A stand-in callback receiver, forwarding every delivery.
+async fn callback_receiver() -> (String, tokio::sync::mpsc::Receiver<(HeaderMap, Bytes)>) {
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    let app = Router::new().route(
+        "/hook",
+        post(move |headers: HeaderMap, body: Bytes| {
+            let tx = tx.clone();
+            async move {
+                tx.send((headers, body)).await.unwrap();
+                StatusCode::OK
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (format!("http://{addr}/hook"), rx)
+}
+
+#[tokio::test]
+async fn test_report_job_callback() {
+    let dir = std::env::temp_dir().join(format!("jobs-it-callback-{}", std::process::id()));
+    let router = create_jobs_router(&dir.display().to_string());
+    let (callback_url, mut deliveries) = callback_receiver().await;
+
+    let create = Request::builder()
+        .uri("/api/reports/jobs")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({
+                "name": "weekly",
+                "content": "= Weekly",
+                "callback_url": callback_url,
+                "callback_secret": "secret",
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let response = router.clone().oneshot(create).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let job: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let id = job["id"].as_str().unwrap().to_string();
+
+    let (headers, body) = tokio::time::timeout(std::time::Duration::from_secs(10), deliveries.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let timestamp: i64 = headers["x-kube-eye-timestamp"].to_str().unwrap().parse().unwrap();
+    assert_eq!(
+        headers.get("x-kube-eye-signature").unwrap().to_str().unwrap(),
+        webhook::signature("secret", timestamp, &body)
+    );
+    let notification: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(notification["job_id"], id.as_str());
+    assert_eq!(notification["status"], "done");
+    assert_eq!(
+        notification["download_url"],
+        format!("https://export.example.com/api/reports/jobs/{id}/file")
+    );
+
+    let response = router
+        .oneshot(job_request("GET", &format!("/api/reports/jobs/{id}/file")))
+        .await
+        .unwrap();
+    let pdf = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(notification["sha256"], format!("{:x}", Sha256::digest(&pdf)));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_report_job_rejects_invalid_callback() {
+    let dir = std::env::temp_dir().join(format!("jobs-it-invalid-{}", std::process::id()));
+    let router = create_jobs_router(&dir.display().to_string());
+    let create = Request::builder()
+        .uri("/api/reports/jobs")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({ "name": "weekly", "content": "= Weekly", "callback_url": "ftp://host" })
+                .to_string(),
+        ))
+        .unwrap();
+    let response = router.oneshot(create).await.unwrap();
+    assert_ne!(response.status(), StatusCode::ACCEPTED);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_report_job_rejects_callback_host_not_allowed() {
+    let dir = std::env::temp_dir().join(format!("jobs-it-callback-host-{}", std::process::id()));
+    let router = create_jobs_router(&dir.display().to_string());
+    let create = Request::builder()
+        .uri("/api/reports/jobs")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({
+                "name": "weekly",
+                "content": "= Weekly",
+                "callback_url": "http://169.254.169.254/latest/meta-data",
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let response = router.oneshot(create).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_api_rejects_unknown_token() {
+    let request = Request::builder()
+        .uri("/api/client_config")
+        .header("Authorization", "Bearer other")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_router_serves_only_enabled_route_groups() {
+    let server = Server::new(create_test_server_config(), create_test_client_config());
+    let state = ServerState::new(
+        &server.config,
+        create_test_client_config(),
+        create_test_typst_config(),
+    )
+    .unwrap();
+    let router = server.router_with(state, &[RouteGroup::Static, RouteGroup::ClientConfig]);
+
+    let request = Request::builder()
+        .uri("/api/client_config")
+        .header("Authorization", "Bearer token")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = router
+        .clone()
+        .oneshot(report_request(json!({ "content": "= Hello" })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    for uri in ["/metrics", "/readyz"] {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let router = create_test_router();
+
+    let response = router
+        .clone()
+        .oneshot(report_request(json!({ "name": "report", "content": "#undefined_fn()" })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let request = Request::builder()
+        .uri("/api/client_config")
+        .header("Authorization", "Bearer wrong")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let request = Request::builder().uri("/metrics").body(Body::empty()).unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(text.contains(
+        r#"kube_eye_export_http_requests_total{method="POST",route="/api/report",status="422"}"#
+    ));
+    assert!(text.contains(r#"kube_eye_export_render_failures_total{kind="compile",theme="default"}"#));
+    assert!(text.contains(r#"kube_eye_export_auth_failures_total{reason="invalid_token"}"#));
+}
+
+#[tokio::test]
+async fn test_report_endpoint_rate_limited() {
+    let mut config = create_test_server_config();
+    config.rate_limit = Some(RateLimitConfig {
+        burst: 1,
+        per_minute: 1,
+        max_concurrent_renders: 1,
+    });
+    let server = Server::new(config, create_test_client_config());
+    let state = ServerState::new(
+        &server.config,
+        create_test_client_config(),
+        create_test_typst_config(),
+    )
+    .unwrap();
+    let router = server.router(state);
+
+    let response = router
+        .clone()
+        .oneshot(report_request(json!({ "name": "report", "content": "= Hello" })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("x-ratelimit-remaining").unwrap(), "0");
+
+    let response = router
+        .clone()
+        .oneshot(report_request(json!({ "name": "report", "content": "= Hello" })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "60");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], 1008);
+
+    // Client config is not rate limited.
+    let request = Request::builder()
+        .uri("/api/client_config")
+        .header("Authorization", "Bearer token")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_api_denies_unauthorized_theme_and_client_config() {
+    let mut config = create_test_server_config();
+    config.authorization.rules = vec![AuthorizationRule {
+        users: vec!["tester".to_string()],
+        themes: vec!["internal".to_string()],
+        ..Default::default()
+    }];
+    let server = Server::new(config, create_test_client_config());
+    let state = ServerState::new(
+        &server.config,
+        create_test_client_config(),
+        create_test_typst_config(),
+    )
+    .unwrap();
+    let router = server.router(state);
+
+    let response = router
+        .clone()
+        .oneshot(report_request(json!({ "name": "report", "content": "= Hello" })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let request = Request::builder()
+        .uri("/api/client_config")
+        .header("Authorization", "Bearer token")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_report_endpoint_unknown_theme() {
+    let request = Request::builder()
+        .uri("/api/report")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({ "name": "report", "content": "= Hello", "theme": "missing" }).to_string(),
+        ))
+        .unwrap();
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_report_endpoint_rejects_content_and_template() {
+    let response = create_test_router()
+        .oneshot(report_request(json!({
+            "name": "report",
+            "content": "= Hello",
+            "template": "template.typ"
+        })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], 1013);
+}
+
+#[tokio::test]
+async fn test_unknown_theme_is_not_a_metrics_label() {
+    let router = create_test_router();
+    let response = router
+        .clone()
+        .oneshot(report_request(
+            json!({ "name": "report", "content": "= Hello", "theme": "random-7f3a" }),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let request = Request::builder().uri("/metrics").body(Body::empty()).unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!text.contains("random-7f3a"));
+    assert!(text.contains(r#"theme="unknown""#));
+}
+
+fn report_request(body: serde_json::Value) -> Request<Body> {
+    Request::builder()
+        .uri("/api/report")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_report_batch_endpoint() {
+    let request = Request::builder()
+        .uri("/api/report/batch")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({
+                "name": "all namespaces",
+                "reports": [
+                    { "name": "kube-system", "content": "= System" },
+                    { "name": "broken", "content": "#unknown-function()" },
+                    { "name": "kube-system", "content": "= System again" },
+                    { "name": "icons", "content": "a", "format": "png" },
+                ]
+            })
+            .to_string(),
+        ))
+        .unwrap();
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/zip"
+    );
+    let disposition = response.headers().get("content-disposition").unwrap();
+    assert!(
+        disposition
+            .to_str()
+            .unwrap()
+            .ends_with("all%20namespaces.zip")
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let mut zip = ZipArchive::new(std::io::Cursor::new(body)).unwrap();
+    let mut names: Vec<&str> = zip.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "errors.json",
+            "icons.png",
+            "kube%2Dsystem-2.pdf",
+            "kube%2Dsystem.pdf"
+        ]
+    );
+    let mut errors = String::new();
+    zip.by_name("errors.json")
+        .unwrap()
+        .read_to_string(&mut errors)
+        .unwrap();
+    let errors: serde_json::Value = serde_json::from_str(&errors).unwrap();
+    assert_eq!(errors.as_array().unwrap().len(), 1);
+    assert_eq!(errors[0]["index"], 1);
+    assert_eq!(errors[0]["name"], "broken");
+    assert!(!errors[0]["diagnostics"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_report_batch_endpoint_counts_reports_against_quota() {
+    let mut config = create_test_server_config();
+    config.rate_limit = Some(RateLimitConfig {
+        burst: 2,
+        per_minute: 1,
+        max_concurrent_renders: 1,
+    });
+    let server = Server::new(config, create_test_client_config());
+    let state = ServerState::new(
+        &server.config,
+        create_test_client_config(),
+        create_test_typst_config(),
+    )
+    .unwrap();
+    let request = Request::builder()
+        .uri("/api/report/batch")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({
+                "name": "quota",
+                "reports": [
+                    { "name": "first", "content": "= First" },
+                    { "name": "second", "content": "= Second" },
+                ]
+            })
+            .to_string(),
+        ))
+        .unwrap();
+
+    // The batch takes one request of the burst and its reports one each.
+    let response = server.router(state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let mut zip = ZipArchive::new(std::io::Cursor::new(body)).unwrap();
+    assert_eq!(zip.len(), 2);
+    let mut errors = String::new();
+    zip.by_name("errors.json")
+        .unwrap()
+        .read_to_string(&mut errors)
+        .unwrap();
+    let errors: serde_json::Value = serde_json::from_str(&errors).unwrap();
+    assert_eq!(errors.as_array().unwrap().len(), 1);
+    assert!(
+        errors[0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Too many requests")
+    );
+}
+
+#[tokio::test]
+async fn test_report_batch_endpoint_rejects_empty_batch() {
+    let request = Request::builder()
+        .uri("/api/report/batch")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(
+            json!({ "name": "empty", "reports": [] }).to_string(),
+        ))
+        .unwrap();
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_ne!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_report_endpoint_png_single_page() {
+    let request = report_request(json!({
+        "name": "report",
+        "content": "#set page(width: 20pt, height: 10pt)\na",
+        "format": "png",
+        "pixel_per_pt": 1.0
+    }));
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("content-type").unwrap(), "image/png");
+    let disposition = response.headers().get("content-disposition").unwrap();
+    assert!(disposition.to_str().unwrap().ends_with("report.png"));
+}
+
+#[tokio::test]
+async fn test_report_endpoint_rejects_invalid_pixel_per_pt() {
+    for pixel_per_pt in [0.0, -1.0, 1000.0] {
+        let request = report_request(json!({
+            "name": "report",
+            "content": "a",
+            "format": "png",
+            "pixel_per_pt": pixel_per_pt
+        }));
+        let response = create_test_router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let request = Request::builder()
+        .uri("/api/report/default/template.typ?format=png&pixel_per_pt=NaN")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from("{}"))
+        .unwrap();
+    let response = create_demo_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_report_endpoint_multi_page_zip() {
+    let request = report_request(json!({
+        "name": "report",
+        "content": "a#pagebreak()b",
+        "format": "svg"
+    }));
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/zip"
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.starts_with(b"PK"));
+}
+
+#[tokio::test]
+async fn test_report_endpoint_json_bundle() {
+    let request = report_request(json!({
+        "name": "report",
+        "content": "a#pagebreak()b#pagebreak()c",
+        "format": "svg",
+        "pages": "1,3",
+        "bundle": "json"
+    }));
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let pages: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let pages = pages.as_array().unwrap();
+    assert_eq!(pages.len(), 2);
+    assert_eq!(pages[1]["page"], 3);
+    assert_eq!(pages[1]["content_type"], "image/svg+xml");
+}
+
+fn create_demo_typst_config() -> TypstConfig {
+    let mut themes = HashMap::new();
+    themes.insert("default".to_string(), Theme {
+        icons: vec![],
+        themplates: HashMap::from([(
+            "template.typ".to_string(),
+            "template/template.typ".to_string(),
+        )]),
+    });
+    TypstConfig {
+        assets_dir: "./examples/demo/assets".to_string(),
+        assets_s3: None,
+        themes,
+        icons: HashMap::new(),
+        render: Default::default(),
+        lenient: false,
+    }
+}
+
+fn create_demo_router() -> Router {
+    let server = Server::new(create_test_server_config(), create_test_client_config());
+    let state = ServerState::new(
+        &server.config,
+        create_test_client_config(),
+        create_demo_typst_config(),
+    )
+    .unwrap();
+    server.router(state)
+}
+
+#[tokio::test]
+async fn test_reload_typst_config() {
+    let server = Server::new(create_test_server_config(), create_test_client_config());
+    let state = ServerState::new(
+        &server.config,
+        create_test_client_config(),
+        create_demo_typst_config(),
+    )
+    .unwrap();
+    let router = server.router(state.clone());
+
+    // Missing template: rejected, the running config stays.
+    let mut broken = create_demo_typst_config();
+    broken.themes.get_mut("default").unwrap().themplates.insert(
+        "missing.typ".to_string(),
+        "template/missing.typ".to_string(),
+    );
+    assert!(state.reload_typst(broken).is_err());
+    assert_eq!(state.typst.load().config.themes["default"].themplates.len(), 1);
+
+    let mut config = create_demo_typst_config();
+    let theme = Theme {
+        icons: vec![],
+        themplates: HashMap::new(),
+    };
+    config.themes.insert("plain".to_string(), theme);
+    state.reload_typst(config).unwrap();
+    assert!(state.typst.load().config.themes.contains_key("plain"));
+
+    let response = router
+        .oneshot(report_request(
+            json!({ "name": "report", "content": "= Hello", "theme": "plain" }),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_livez_endpoint() {
+    let request = Request::builder().uri("/livez").body(Body::empty()).unwrap();
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_readyz_endpoint_ready() {
+    let client_config = Arc::new(ArcSwap::from_pointee(json!({ "report_title": {} })));
+    let server = Server::new(create_test_server_config(), Arc::clone(&client_config));
+    let state = ServerState::new(&server.config, client_config, create_demo_typst_config()).unwrap();
+    let request = Request::builder().uri("/readyz").body(Body::empty()).unwrap();
+
+    let response = server.router(state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["ready"], true);
+    let checks: Vec<&str> = body["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|check| check["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(checks, vec!["assets_dir", "theme:default", "smoke_compile", "client_config"]);
+}
+
+#[tokio::test]
+async fn test_readyz_endpoint_client_config_reload_failed() {
+    let client_config = Arc::new(ArcSwap::from_pointee(json!({ "report_title": {} })));
+    let server = Server::new(create_test_server_config(), Arc::clone(&client_config));
+    let state = ServerState::new(&server.config, client_config, create_demo_typst_config()).unwrap();
+    state
+        .client_config_error
+        .store(Some(Arc::new("invalid yaml".to_string())));
+    let request = Request::builder().uri("/readyz").body(Body::empty()).unwrap();
+
+    let response = server.router(state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["checks"][3]["name"], "client_config");
+    assert_eq!(
+        body["checks"][3]["problems"][0],
+        "client config failed to load: invalid yaml"
+    );
+}
+
+#[tokio::test]
+async fn test_readyz_endpoint_shutting_down() {
+    let client_config = Arc::new(ArcSwap::from_pointee(json!({ "report_title": {} })));
+    let server = Server::new(create_test_server_config(), Arc::clone(&client_config));
+    let state = ServerState::new(&server.config, client_config, create_demo_typst_config()).unwrap();
+    state.shutdown.cancel();
+    let request = Request::builder().uri("/readyz").body(Body::empty()).unwrap();
+
+    let response = server.router(state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["checks"][0]["name"], "shutdown");
+    assert_eq!(body["checks"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_readyz_endpoint_draining_still_serves() {
+    let client_config = Arc::new(ArcSwap::from_pointee(json!({ "report_title": {} })));
+    let server = Server::new(create_test_server_config(), Arc::clone(&client_config));
+    let state = ServerState::new(&server.config, client_config, create_demo_typst_config()).unwrap();
+    state.draining.cancel();
+    assert!(!state.shutdown.is_cancelled());
+    let router = server.router(state);
+
+    let request = Request::builder().uri("/readyz").body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let response = router
+        .oneshot(report_request(json!({ "name": "report", "content": "= Hello" })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_readyz_endpoint_not_ready() {
+    let request = Request::builder().uri("/readyz").body(Body::empty()).unwrap();
+
+    let response = create_test_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["ready"], false);
+    assert_eq!(body["checks"][0]["name"], "assets_dir");
+    assert_eq!(body["checks"][0]["ok"], false);
+}
+
+#[tokio::test]
+async fn test_report_template_endpoint() {
+    let request = Request::builder()
+        .uri("/api/report/default/template.typ?format=svg")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from(json!({ "cluster": "demo" }).to_string()))
+        .unwrap();
+
+    let response = create_demo_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "image/svg+xml"
+    );
+    let disposition = response.headers().get("content-disposition").unwrap();
+    assert!(disposition.to_str().unwrap().ends_with("template.svg"));
+}
+
+#[tokio::test]
+async fn test_report_template_endpoint_unknown_template() {
+    let request = Request::builder()
+        .uri("/api/report/default/missing.typ")
+        .method("POST")
+        .header("Authorization", "Bearer token")
+        .header("content-type", "application/json")
+        .body(Body::from("{}"))
+        .unwrap();
+
+    let response = create_demo_router().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 }
 
 #[test]